@@ -0,0 +1,182 @@
+use {
+    crate::prelude::ConnectionError,
+    std::{fmt::Display, net::Ipv6Addr, time::Duration},
+    tokio::net::TcpStream,
+};
+
+/// The port the server listens on when none is given.
+pub const DEFAULT_PORT: u16 = 42530;
+/// How long to wait for each address of the server to accept the connection before trying the next one.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// ### Address
+///
+/// The host and port of a server, as typed by the user.
+///
+/// The host can be a DNS name, an IPv4 address or an IPv6 address. It is only resolved when connecting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Address {
+    /// Parses `host`, `host:port`, `[ipv6]`, `[ipv6]:port` or a bare IPv6 literal, using the default port if there is none.
+    pub fn parse(s: &str) -> Result<Self, ConnectionError> {
+        let s = s.trim();
+        let invalid = || ConnectionError::InvalidAddress(s.to_owned());
+
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            // Bracketed IPv6, with or without a port.
+            let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+            }
+        } else if s.parse::<Ipv6Addr>().is_ok() {
+            // A bare IPv6 literal can't have a port, the colons would be ambiguous.
+            (s, None)
+        } else {
+            match s.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (s, None),
+            }
+        };
+
+        let port = match port {
+            Some(p) => p
+                .parse::<u16>()
+                .ok()
+                .filter(|p| *p != 0)
+                .ok_or_else(invalid)?,
+            None => DEFAULT_PORT,
+        };
+        if host.is_empty() || host.chars().any(|c| c.is_whitespace() || c == '/') {
+            return Err(invalid());
+        }
+        if s.starts_with('[') && host.parse::<Ipv6Addr>().is_err() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+        })
+    }
+
+    /// Resolves the host and connects to the first address that accepts the connection.
+    ///
+    /// Every address the host resolves to is tried in turn (e.g. both the IPv6 and IPv4 ones), and the error from the last one is returned if none work.
+    /// Each one gets `CONNECT_TIMEOUT`, so one that never answers doesn't stop the rest from being tried.
+    pub async fn connect(&self) -> Result<TcpStream, ConnectionError> {
+        let addrs = tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| ConnectionError::Resolve(self.host.clone(), e))?;
+
+        let mut last_err = None;
+        for addr in addrs {
+            match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => last_err = Some(e),
+                Err(_) => last_err = Some(std::io::ErrorKind::TimedOut.into()),
+            }
+        }
+        Err(match last_err {
+            Some(e) => ConnectionError::Connect(self.to_string(), e),
+            None => ConnectionError::Resolve(
+                self.host.clone(),
+                std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses found"),
+            ),
+        })
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{Address, DEFAULT_PORT},
+        crate::prelude::ConnectionError,
+        tokio::net::TcpListener,
+    };
+
+    fn addr(host: &str, port: u16) -> Address {
+        Address {
+            host: host.to_owned(),
+            port,
+        }
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(
+            Address::parse("example.com").unwrap(),
+            addr("example.com", DEFAULT_PORT)
+        );
+        assert_eq!(
+            Address::parse("example.com:4000").unwrap(),
+            addr("example.com", 4000)
+        );
+        assert_eq!(
+            Address::parse(" 10.0.0.1:4000 ").unwrap(),
+            addr("10.0.0.1", 4000)
+        );
+        assert_eq!(Address::parse("::1").unwrap(), addr("::1", DEFAULT_PORT));
+        assert_eq!(Address::parse("[::1]").unwrap(), addr("::1", DEFAULT_PORT));
+        assert_eq!(
+            Address::parse("[fe80::1]:4000").unwrap(),
+            addr("fe80::1", 4000)
+        );
+
+        for bad in [
+            "",
+            ":4000",
+            "example.com:",
+            "example.com:port",
+            "example.com:70000",
+            "example.com:0",
+            "a:b:c",
+            "[example.com]:4000",
+            "[::1]4000",
+            "[::1",
+            "exa mple.com",
+        ] {
+            assert!(
+                matches!(Address::parse(bad), Err(ConnectionError::InvalidAddress(_))),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn display_test() {
+        assert_eq!(addr("example.com", 4000).to_string(), "example.com:4000");
+        assert_eq!(addr("::1", 4000).to_string(), "[::1]:4000");
+    }
+
+    /// `localhost` may resolve to `::1` before `127.0.0.1`, so this only passes if every address is tried.
+    #[tokio::test]
+    async fn connect_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        Address::parse(&format!("localhost:{port}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            addr("host.invalid", port).connect().await,
+            Err(ConnectionError::Resolve(..))
+        ));
+    }
+}
@@ -0,0 +1,854 @@
+use {
+    chat_app::{
+        crypto::{fingerprint, GroupKey, Identity},
+        message::Target,
+        prelude::*,
+        protocol::{Frame, FrameCodec, Presence, ProtocolError, HISTORY_PAGE},
+    },
+    futures::{SinkExt, StreamExt},
+    openssl::rsa::Rsa,
+    std::{
+        collections::{HashMap, HashSet, VecDeque},
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        time::SystemTime,
+    },
+    tokio::{
+        net::{TcpListener, TcpStream},
+        sync::{
+            broadcast::{self, error::RecvError},
+            mpsc,
+        },
+    },
+    tokio_util::codec::Framed,
+};
+
+const DEFAULT_PORT: u16 = 42530;
+/// How many messages are kept for each room, for clients that missed them.
+const ROOM_HISTORY: usize = 1000;
+/// How many bytes of messages are kept across every room. Past it, the rooms that went the longest without a message are forgotten.
+const HISTORY_BYTES: usize = 256 * 1024 * 1024;
+
+/// The messages kept for a room, oldest first: each one's sequence number and sealed contents.
+type Backlog = VecDeque<(u64, Vec<u8>)>;
+
+/// ### History
+///
+/// The last `ROOM_HISTORY` messages sent to each room, still sealed, with their sequence numbers.
+///
+/// Anyone can send to as many rooms as they like, so there is a limit on the bytes kept across all of them too.
+struct History {
+    rooms: HashMap<String, Backlog>,
+    /// How many bytes the messages kept take up.
+    bytes: usize,
+    /// The most bytes kept before whole rooms are forgotten.
+    max_bytes: usize,
+}
+
+impl History {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            rooms: HashMap::new(),
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    /// Keeps a message sent to `room`, forgetting the oldest one in the room if it has too many,
+    /// and the rooms that went the longest without a message if there are too many bytes kept.
+    fn keep(&mut self, room: String, seq: u64, sealed: Vec<u8>) {
+        let kept = self.rooms.entry(room).or_default();
+        if kept.len() == ROOM_HISTORY {
+            if let Some((_, old)) = kept.pop_front() {
+                self.bytes -= size(&old);
+            }
+        }
+        self.bytes += size(&sealed);
+        kept.push_back((seq, sealed));
+
+        while self.bytes > self.max_bytes {
+            // Sequence numbers only go up, so the room with the lowest last one had a message the longest ago.
+            let Some(oldest) = self
+                .rooms
+                .iter()
+                .min_by_key(|(_, kept)| kept.back().map(|(seq, _)| *seq))
+                .map(|(room, _)| room.clone())
+            else {
+                break;
+            };
+            if let Some(kept) = self.rooms.remove(&oldest) {
+                self.bytes -= kept.iter().map(|(_, sealed)| size(sealed)).sum::<usize>();
+            }
+        }
+    }
+}
+
+/// The memory a message kept in the history takes up, counting its sequence number so even empty ones add up.
+fn size(sealed: &[u8]) -> usize {
+    std::mem::size_of::<(u64, Vec<u8>)>() + sealed.len()
+}
+
+/// ### Server
+///
+/// The state shared by every client connected to the server.
+struct Server {
+    /// The server's long-term identity key. Clients pin its fingerprint.
+    identity: Identity,
+    /// The group key. Every client is handed a copy wrapped with its own public key during the handshake.
+    group_key: GroupKey,
+    /// Every ENC frame sent by a client is fanned out to all of the clients through this channel, and each client picks out the rooms it is in.
+    tx: broadcast::Sender<Frame>,
+    /// The clients that have told us their username, by username.
+    users: Mutex<HashMap<String, User>>,
+    /// When the server started, in nanoseconds since the Unix epoch. Clients are told it in the handshake, so they know when the sequence numbers started again.
+    epoch: u64,
+    /// The sequence number given to the last message passed on. They start again from 1 when the server restarts.
+    seq: Mutex<u64>,
+    /// The last messages sent to each room.
+    history: Mutex<History>,
+}
+
+impl Server {
+    fn new(identity: Identity) -> std::result::Result<Self, ConnectionError> {
+        Ok(Self {
+            identity,
+            group_key: GroupKey::generate()?,
+            tx: broadcast::channel(100).0,
+            users: Mutex::new(HashMap::new()),
+            epoch: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64),
+            seq: Mutex::new(0),
+            history: Mutex::new(History::new(HISTORY_BYTES)),
+        })
+    }
+
+    /// Numbers a message to a room, keeps it in the room's history and sends it to every client.
+    fn publish(&self, room: String, sealed: Vec<u8>) {
+        self.sequence(|seq| {
+            self.history
+                .lock()
+                .unwrap()
+                .keep(room.clone(), seq, sealed.clone());
+            _ = self.tx.send(Frame::Enc { room, seq, sealed });
+        });
+    }
+
+    /// The answer to a `HIS` frame: a page of the messages kept for `room` with a sequence number above `after` and below `before` (if it isn't 0), oldest first.
+    ///
+    /// Catching up from `after` gets the oldest `HISTORY_PAGE` of them, followed by a `HIS` frame for the next page if the page is full.
+    /// Otherwise it is the newest of them.
+    fn history(&self, room: &str, after: u64, before: u64) -> Vec<Frame> {
+        let history = self.history.lock().unwrap();
+        let Some(kept) = history.rooms.get(room) else {
+            return vec![];
+        };
+        let matching = kept
+            .iter()
+            .filter(|(seq, _)| *seq > after && (before == 0 || *seq < before));
+        let page: Vec<_> = if after > 0 {
+            matching.take(HISTORY_PAGE).collect()
+        } else {
+            let mut page: Vec<_> = matching.rev().take(HISTORY_PAGE).collect();
+            page.reverse();
+            page
+        };
+
+        let next = match page.last() {
+            Some((last, _)) if after > 0 && page.len() == HISTORY_PAGE => Some(Frame::History {
+                room: room.to_owned(),
+                after: *last,
+                before,
+            }),
+            _ => None,
+        };
+        page.into_iter()
+            .map(|(seq, sealed)| Frame::Enc {
+                room: room.to_owned(),
+                seq: *seq,
+                sealed: sealed.clone(),
+            })
+            .chain(next)
+            .collect()
+    }
+
+    /// Gives a message the next sequence number and passes it on with `send`.
+    ///
+    /// Nothing else can be given a number until `send` returns, so messages are passed on in the order they are numbered.
+    fn sequence<T>(&self, send: impl FnOnce(u64) -> T) -> T {
+        let mut seq = self.seq.lock().unwrap();
+        *seq += 1;
+        send(*seq)
+    }
+
+    /// The usernames of everyone online, in order.
+    fn roster(&self) -> Vec<String> {
+        let mut users: Vec<_> = self.users.lock().unwrap().keys().cloned().collect();
+        users.sort();
+        users
+    }
+}
+
+/// ### User
+///
+/// What the server needs to pass a direct message on to a client.
+struct User {
+    /// The client's public RSA key, DER-encoded, handed to anyone who wants to send it a direct message.
+    key: Vec<u8>,
+    /// Frames sent to this channel are written to the client.
+    tx: mpsc::Sender<Frame>,
+}
+
+/// ### Nick
+///
+/// The username a client has taken. It is freed again when the client disconnects.
+///
+/// Every client is told when it is taken, changed or freed.
+struct Nick {
+    server: Arc<Server>,
+    name: Option<String>,
+}
+
+impl Nick {
+    /// Takes `name` for the client, freeing the one it had before.
+    ///
+    /// Returns why it was refused if another client has it.
+    fn set(&mut self, name: String, user: User) -> std::result::Result<(), String> {
+        if self.name.as_ref() == Some(&name) {
+            return Ok(());
+        }
+        let mut users = self.server.users.lock().unwrap();
+        if users.contains_key(&name) {
+            return Err(format!("the name {name} is taken"));
+        }
+        let presence = match self.name.take() {
+            Some(old) => {
+                users.remove(&old);
+                Presence::Renamed {
+                    from: old,
+                    to: name.clone(),
+                }
+            }
+            None => Presence::Joined(name.clone()),
+        };
+        users.insert(name.clone(), user);
+        self.name = Some(name);
+        _ = self.server.tx.send(Frame::Presence(presence));
+        Ok(())
+    }
+}
+
+impl Drop for Nick {
+    fn drop(&mut self) {
+        if let Some(name) = self.name.take() {
+            self.server.users.lock().unwrap().remove(&name);
+            _ = self.server.tx.send(Frame::Presence(Presence::Left(name)));
+        }
+    }
+}
+
+/// ### The chat server.
+///
+/// Usage: `chat_server [address] [identity file]`
+///
+/// Listens on `address` (`0.0.0.0:42530` by default) and spawns a task for every client that connects.
+/// The identity key is read from `identity file` (`server_identity.pem` in the config directory by default), and created there if it does not exist yet.
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let addr = args.next().unwrap_or(format!("0.0.0.0:{DEFAULT_PORT}"));
+    let identity_path = args
+        .next()
+        .map(PathBuf::from)
+        .or_else(|| dirs::config_dir().map(|d| d.join("chat_app").join("server_identity.pem")))
+        .unwrap_or(PathBuf::from("server_identity.pem"));
+
+    let (identity, generated) = load_identity(&identity_path)?;
+    if generated {
+        println!(
+            "Generated a new identity key at {}",
+            identity_path.display()
+        );
+    }
+    println!(
+        "Identity key fingerprint: {}",
+        fingerprint(&identity.public_key()?)
+    );
+
+    let listener = TcpListener::bind(&addr).await?;
+    println!("Listening on {}", listener.local_addr()?);
+
+    let server = Arc::new(Server::new(identity)?);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            println!("{peer} connected");
+            match client_loop(stream, server).await {
+                Ok(_) => println!("{peer} disconnected"),
+                Err(e) => eprintln!("{peer} disconnected: {}", error_chain(&e)),
+            }
+        });
+    }
+}
+
+/// ### The per-client loop.
+///
+/// Performs the `PUB` -> `IDN` + `PRV` handshake, proving who we are and handing the client the group key,
+/// then keeps track of the rooms the client joins and leaves with `JON` and `LEV`,
+/// forwards every `ENC` frame the client sends to a room it is in to the broadcast channel,
+/// and writes every frame recieved from the broadcast channel for a room it is in back to the client.
+///
+/// Once the client has taken a username with `NCK`, other clients can get its public key with `KRQ` and send it `DMS` frames,
+/// which are passed on to it alone. `WHO` lists the usernames taken, and the client is sent `PRS` whenever that changes.
+///
+/// Every room keeps its last messages, and `HIS` asks for the ones the client missed, or for older ones.
+///
+/// `TYP` frames are passed on to the rest of the room, or to the user, with the client's username. They are dropped rather than refused if they can't be.
+async fn client_loop(
+    stream: TcpStream,
+    server: Arc<Server>,
+) -> std::result::Result<(), ConnectionError> {
+    let mut stream = Framed::new(stream, FrameCodec::default());
+
+    // Subscribe before the handshake so no frames are missed in between.
+    let mut brx = server.tx.subscribe();
+
+    // Get the client's public key.
+    let pub_key = match stream.next().await {
+        Some(Ok(Frame::Pub(k))) => k,
+        Some(Ok(frame)) => return Err(ProtocolError::UnexpectedFrame(*frame.tag()).into()),
+        Some(Err(e)) => return Err(e),
+        None => return Ok(()),
+    };
+    let cl_rsa = Rsa::public_key_from_der(&pub_key)?;
+
+    // Wrap the group key with the client's public key, and sign both so the client knows it came from us.
+    let key_enc = server.group_key.wrap(&cl_rsa)?;
+    let signature = server.identity.sign(&[&pub_key[..], &key_enc].concat())?;
+    stream
+        .send(Frame::Idn {
+            key: server.identity.public_key()?,
+            signature,
+            epoch: server.epoch,
+        })
+        .await?;
+    stream.send(Frame::Prv(key_enc)).await?;
+
+    // The rooms the client is in.
+    let mut rooms = HashSet::new();
+
+    // The client's username, and the channel other clients send it direct messages through.
+    let mut nick = Nick {
+        server: server.clone(),
+        name: None,
+    };
+    let (dtx, mut drx) = mpsc::channel::<Frame>(25);
+
+    // Main loop
+    loop {
+        tokio::select! {
+            result = stream.next() => { // Check for a frame from the client.
+                match result {
+                    Some(Ok(Frame::Enc { room, sealed, .. })) if rooms.contains(&room) => {
+                        server.publish(room, sealed); // Send it to everyone in the room.
+                    },
+                    Some(Ok(Frame::Enc { room, .. })) => eprintln!("A client sent a message to #{room} without joining it"),
+                    Some(Ok(Frame::Join(room))) => _ = rooms.insert(room),
+                    Some(Ok(Frame::History { room, after, before })) if rooms.contains(&room) => {
+                        for frame in server.history(&room, after, before) {
+                            stream.send(frame).await?;
+                        }
+                    },
+                    Some(Ok(Frame::History { room, .. })) => eprintln!("A client asked for the history of #{room} without joining it"),
+                    Some(Ok(Frame::Leave(room))) => _ = rooms.remove(&room),
+                    Some(Ok(Frame::Nick(name))) => {
+                        let user = User { key: pub_key.clone(), tx: dtx.clone() };
+                        match nick.set(name.clone(), user) {
+                            Ok(_) => {
+                                // Let the client know it worked, and who else is here.
+                                stream.send(Frame::Nick(name)).await?;
+                                stream.send(Frame::Users(server.roster())).await?;
+                            },
+                            Err(reason) => stream.send(Frame::Error(reason)).await?,
+                        }
+                    },
+                    Some(Ok(Frame::Who)) => stream.send(Frame::Users(server.roster())).await?,
+                    Some(Ok(Frame::KeyRequest(user))) => {
+                        // An empty key means the user isn't online.
+                        let key = server.users.lock().unwrap().get(&user).map(|u| u.key.clone()).unwrap_or_default();
+                        stream.send(Frame::Key { user, key }).await?;
+                    },
+                    Some(Ok(Frame::Direct { peer, sender_key, wrapped, sealed, signature, .. })) => {
+                        let Some(from) = nick.name.clone() else {
+                            stream.send(Frame::Error("take a username before sending direct messages".to_owned())).await?;
+                            continue;
+                        };
+                        let tx = server.users.lock().unwrap().get(&peer).map(|u| u.tx.clone());
+                        match tx {
+                            // The recipient sees who it came from instead of who it's for.
+                            Some(tx) => if !server.sequence(|seq| tx.try_send(Frame::Direct { peer: from, seq, sender_key, wrapped, sealed, signature }).is_ok()) {
+                                // They are falling behind. The sender should know the message never got there.
+                                stream.send(Frame::Error(format!("{peer} is not keeping up, so your direct message was dropped"))).await?;
+                            },
+                            None => stream.send(Frame::Error(format!("{peer} is not online"))).await?,
+                        }
+                    },
+                    Some(Ok(Frame::Typing { to, .. })) => {
+                        // Whoever is typing is named by the server, not the client.
+                        let Some(from) = nick.name.clone() else { continue };
+                        match to {
+                            Target::Room(room) if rooms.contains(&room) => _ = server.tx.send(Frame::Typing { to: Target::Room(room), from }),
+                            Target::Room(_) => {},
+                            Target::User(peer) => {
+                                let tx = server.users.lock().unwrap().get(&peer).map(|u| u.tx.clone());
+                                if let Some(tx) = tx {
+                                    _ = tx.try_send(Frame::Typing { to: Target::User(peer), from });
+                                }
+                            },
+                        }
+                    },
+                    Some(Ok(frame)) => return Err(ProtocolError::UnexpectedFrame(*frame.tag()).into()),
+                    Some(Err(e)) => return Err(e),
+                    None => break, // Client closed the connection.
+                }
+            },
+            result = brx.recv() => { // Check for a frame to be sent to the client.
+                match result {
+                    Ok(Frame::Enc { room, seq, sealed }) if rooms.contains(&room) => stream.send(Frame::Enc { room, seq, sealed }).await?,
+                    Ok(frame @ Frame::Presence(_)) => stream.send(frame).await?,
+                    Ok(Frame::Typing { to: Target::Room(room), from }) if rooms.contains(&room) && nick.name.as_ref() != Some(&from) => {
+                        stream.send(Frame::Typing { to: Target::Room(room), from }).await?;
+                    },
+                    Ok(_) => {}, // A room the client is not in.
+                    Err(RecvError::Lagged(n)) => eprintln!("A client fell behind by {n} messages"),
+                    Err(RecvError::Closed) => break,
+                }
+            },
+            Some(frame) = drx.recv() => stream.send(frame).await?, // A direct message for the client.
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{size, History, Server},
+        chat_app::{
+            crypto::{verify, GroupKey, Identity},
+            message::Target,
+            protocol::{Frame, FrameCodec, Presence},
+        },
+        futures::{SinkExt, StreamExt},
+        openssl::rsa::Rsa,
+        std::{net::SocketAddr, sync::Arc},
+        tokio::net::{TcpListener, TcpStream},
+        tokio_util::codec::Framed,
+    };
+
+    type Client = Framed<TcpStream, FrameCodec>;
+
+    /// Starts a server that accepts any number of clients.
+    async fn start_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(Server::new(Identity::generate().unwrap()).unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(super::client_loop(stream, server.clone()));
+            }
+        });
+        addr
+    }
+
+    /// Connects a client, does the handshake and takes the username `name`.
+    async fn connect(addr: SocketAddr, name: &str) -> (Client, Identity) {
+        let mut stream = Framed::new(
+            TcpStream::connect(addr).await.unwrap(),
+            FrameCodec::default(),
+        );
+        let identity = Identity::generate().unwrap();
+        stream
+            .send(Frame::Pub(identity.public_key().unwrap()))
+            .await
+            .unwrap();
+        let Some(Ok(Frame::Idn { .. })) = stream.next().await else {
+            panic!("expected an IDN frame");
+        };
+        let Some(Ok(Frame::Prv(_))) = stream.next().await else {
+            panic!("expected a PRV frame");
+        };
+        stream.send(Frame::Nick(name.to_owned())).await.unwrap();
+        assert_eq!(next(&mut stream).await, Frame::Nick(name.to_owned()));
+        let Frame::Users(users) = next(&mut stream).await else {
+            panic!("expected a USR frame");
+        };
+        assert!(users.contains(&name.to_owned()));
+        (stream, identity)
+    }
+
+    /// Reads the next frame, skipping over presence changes.
+    async fn next(stream: &mut Client) -> Frame {
+        loop {
+            match stream.next().await.unwrap().unwrap() {
+                Frame::Presence(_) => {}
+                frame => return frame,
+            }
+        }
+    }
+
+    /// Connects a client to the server, performs the handshake and checks that an ENC frame is echoed back.
+    #[tokio::test]
+    async fn handshake_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(Server::new(Identity::generate().unwrap()).unwrap());
+        let identity_key = server.identity.public_key().unwrap();
+
+        let sv = server.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            super::client_loop(stream, sv).await.unwrap();
+        });
+
+        let mut stream = Framed::new(
+            TcpStream::connect(addr).await.unwrap(),
+            FrameCodec::default(),
+        );
+        let cl_rsa = Rsa::generate(2048).unwrap();
+        let pub_key = cl_rsa.public_key_to_der().unwrap();
+        stream.send(Frame::Pub(pub_key.clone())).await.unwrap();
+
+        // Read the IDN and PRV frames, check the signature and unwrap the group key.
+        let Some(Ok(Frame::Idn {
+            key,
+            signature,
+            epoch,
+        })) = stream.next().await
+        else {
+            panic!("expected an IDN frame");
+        };
+        assert_eq!(key, identity_key);
+        assert_eq!(epoch, server.epoch);
+        let Some(Ok(Frame::Prv(wrapped))) = stream.next().await else {
+            panic!("expected a PRV frame");
+        };
+        assert!(verify(&key, &[&pub_key[..], &wrapped].concat(), &signature).unwrap());
+        let key = GroupKey::unwrap(&cl_rsa, &wrapped).unwrap().unwrap();
+
+        // Any ENC frame should be sent back to every client in the room, including the one who sent it.
+        stream
+            .send(Frame::Join("general".to_owned()))
+            .await
+            .unwrap();
+        let frame = Frame::seal(&key, "general", b"Hello there!").unwrap();
+        stream.send(frame.clone()).await.unwrap();
+        let Some(Ok(Frame::Enc { room, seq, sealed })) = stream.next().await else {
+            panic!("expected an ENC frame");
+        };
+        assert_eq!(seq, 1); // Numbered by the server.
+        assert_eq!(
+            frame,
+            Frame::Enc {
+                room: room.clone(),
+                seq: 0,
+                sealed: sealed.clone()
+            }
+        );
+        assert_eq!(
+            Frame::open(&server.group_key, &room, &sealed).unwrap(),
+            b"Hello there!"
+        );
+
+        // But not to a room it has left.
+        stream
+            .send(Frame::Leave("general".to_owned()))
+            .await
+            .unwrap();
+        stream.send(Frame::Join("random".to_owned())).await.unwrap();
+        stream
+            .send(Frame::seal(&key, "general", b"Anyone?").unwrap())
+            .await
+            .unwrap();
+        let frame = Frame::seal(&key, "random", b"Hi!").unwrap();
+        stream.send(frame.clone()).await.unwrap();
+        let Frame::Enc { room, sealed, .. } = frame else {
+            panic!("expected an ENC frame");
+        };
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            Frame::Enc {
+                room,
+                seq: 2, // The message to the room it left wasn't numbered.
+                sealed
+            }
+        );
+    }
+
+    /// Sends a direct message from alice to bob, which only bob can open.
+    #[tokio::test]
+    async fn direct_test() {
+        let addr = start_server().await;
+        let (mut alice, alice_id) = connect(addr, "alice").await;
+        let (mut bob, bob_id) = connect(addr, "bob").await;
+
+        // Alice asks for Bob's key, and gets the one he sent in the handshake.
+        alice
+            .send(Frame::KeyRequest("bob".to_owned()))
+            .await
+            .unwrap();
+        let Frame::Key { user, key } = next(&mut alice).await else {
+            panic!("expected a KEY frame");
+        };
+        assert_eq!(user, "bob");
+        assert_eq!(key, bob_id.public_key().unwrap());
+
+        // Bob gets the message, from Alice.
+        let bob_key = Rsa::public_key_from_der(&key).unwrap();
+        alice
+            .send(Frame::seal_direct(&alice_id, &bob_key, "alice", "bob", b"Psst").unwrap())
+            .await
+            .unwrap();
+        let Frame::Direct {
+            peer,
+            seq,
+            sender_key,
+            wrapped,
+            sealed,
+            signature,
+        } = next(&mut bob).await
+        else {
+            panic!("expected a DMS frame");
+        };
+        assert_eq!(peer, "alice");
+        assert_eq!(seq, 1);
+        assert_eq!(sender_key, alice_id.public_key().unwrap());
+        let bob_rsa = bob_id.rsa().unwrap();
+        let opened = Frame::open_direct(
+            &bob_rsa,
+            &peer,
+            "bob",
+            &sender_key,
+            &wrapped,
+            &sealed,
+            &signature,
+        );
+        assert_eq!(opened.unwrap(), b"Psst");
+
+        // Users who aren't online have no key, and can't be messaged.
+        alice
+            .send(Frame::KeyRequest("carol".to_owned()))
+            .await
+            .unwrap();
+        let Frame::Key { key, .. } = next(&mut alice).await else {
+            panic!("expected a KEY frame");
+        };
+        assert!(key.is_empty());
+        alice
+            .send(Frame::seal_direct(&alice_id, &bob_key, "alice", "carol", b"Hi").unwrap())
+            .await
+            .unwrap();
+        assert!(matches!(next(&mut alice).await, Frame::Error(_)));
+
+        // Bob's name can't be taken while he is online.
+        alice.send(Frame::Nick("bob".to_owned())).await.unwrap();
+        assert!(matches!(next(&mut alice).await, Frame::Error(_)));
+        alice.send(Frame::Who).await.unwrap();
+        assert_eq!(
+            next(&mut alice).await,
+            Frame::Users(vec!["alice".to_owned(), "bob".to_owned()])
+        );
+    }
+
+    /// Everyone is told when someone comes online, changes their name or goes offline.
+    #[tokio::test]
+    async fn presence_test() {
+        let addr = start_server().await;
+        let (mut alice, _) = connect(addr, "alice").await;
+        assert_eq!(
+            alice.next().await.unwrap().unwrap(),
+            Frame::Presence(Presence::Joined("alice".to_owned()))
+        );
+
+        let (mut bob, _) = connect(addr, "bob").await;
+        bob.send(Frame::Nick("robert".to_owned())).await.unwrap();
+        drop(bob);
+        for presence in [
+            Presence::Joined("bob".to_owned()),
+            Presence::Renamed {
+                from: "bob".to_owned(),
+                to: "robert".to_owned(),
+            },
+            Presence::Left("robert".to_owned()),
+        ] {
+            assert_eq!(
+                alice.next().await.unwrap().unwrap(),
+                Frame::Presence(presence)
+            );
+        }
+    }
+
+    /// Typing is passed on to everyone else in the room, or to the user alone, with the name of whoever is typing.
+    #[tokio::test]
+    async fn typing_test() {
+        let addr = start_server().await;
+        let (mut alice, _) = connect(addr, "alice").await;
+        let (mut bob, _) = connect(addr, "bob").await;
+        for client in [&mut alice, &mut bob] {
+            client
+                .send(Frame::Join("general".to_owned()))
+                .await
+                .unwrap();
+            // Wait for an answer, so the server has joined the room too.
+            client.send(Frame::Who).await.unwrap();
+            assert!(matches!(next(client).await, Frame::Users(_)));
+        }
+
+        // Alice claims to be carol, but the server knows better. She isn't told about her own typing.
+        alice
+            .send(Frame::Typing {
+                to: Target::Room("general".to_owned()),
+                from: "carol".to_owned(),
+            })
+            .await
+            .unwrap();
+        let typing = Frame::Typing {
+            to: Target::Room("general".to_owned()),
+            from: "alice".to_owned(),
+        };
+        assert_eq!(next(&mut bob).await, typing);
+
+        bob.send(Frame::Typing {
+            to: Target::User("alice".to_owned()),
+            from: "bob".to_owned(),
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            next(&mut alice).await,
+            Frame::Typing {
+                to: Target::User("alice".to_owned()),
+                from: "bob".to_owned(),
+            }
+        );
+    }
+
+    /// Clients can ask for what they missed in a room, and page back through older messages.
+    #[tokio::test]
+    async fn history_test() {
+        let addr = start_server().await;
+        let (mut alice, _) = connect(addr, "alice").await;
+        alice.send(Frame::Join("general".to_owned())).await.unwrap();
+        for n in 1..=3 {
+            let frame = Frame::Enc {
+                room: "general".to_owned(),
+                seq: 0,
+                sealed: vec![n],
+            };
+            alice.send(frame).await.unwrap();
+            assert!(matches!(next(&mut alice).await, Frame::Enc { .. }));
+        }
+
+        let history = |after, before| Frame::History {
+            room: "general".to_owned(),
+            after,
+            before,
+        };
+        let seqs = |frames: Vec<Frame>| -> Vec<u64> {
+            frames
+                .into_iter()
+                .map(|f| match f {
+                    Frame::Enc { seq, sealed, .. } => {
+                        assert_eq!(sealed, [seq as u8]);
+                        seq
+                    }
+                    f => panic!("expected an ENC frame, got {f:?}"),
+                })
+                .collect()
+        };
+
+        // Bob joins late, and catches up.
+        let (mut bob, _) = connect(addr, "bob").await;
+        bob.send(history(0, 0)).await.unwrap();
+        bob.send(Frame::Join("general".to_owned())).await.unwrap();
+        bob.send(history(1, 0)).await.unwrap();
+        bob.send(history(0, 3)).await.unwrap();
+        let mut frames = Vec::new();
+        for _ in 0..4 {
+            frames.push(next(&mut bob).await);
+        }
+        // Nothing for the room before he joined it, then the messages after 1, then the ones before 3.
+        assert_eq!(seqs(frames), [2, 3, 1, 2]);
+    }
+
+    /// A client that missed more than a page of messages gets every one of them, a page at a time.
+    #[tokio::test]
+    async fn catch_up_test() {
+        let addr = start_server().await;
+        let (mut alice, _) = connect(addr, "alice").await;
+        alice.send(Frame::Join("general".to_owned())).await.unwrap();
+        for n in 1..=120 {
+            let frame = Frame::Enc {
+                room: "general".to_owned(),
+                seq: 0,
+                sealed: vec![n],
+            };
+            alice.send(frame).await.unwrap();
+            assert!(matches!(next(&mut alice).await, Frame::Enc { .. }));
+        }
+
+        // Bob saw up to 10, then went away. He asks again for as long as the server says there is more.
+        let (mut bob, _) = connect(addr, "bob").await;
+        bob.send(Frame::Join("general".to_owned())).await.unwrap();
+        bob.send(Frame::History {
+            room: "general".to_owned(),
+            after: 10,
+            before: 0,
+        })
+        .await
+        .unwrap();
+        let mut seqs = Vec::new();
+        let mut pages = 1;
+        while seqs.last() != Some(&120) {
+            match next(&mut bob).await {
+                Frame::Enc { seq, sealed, .. } => {
+                    assert_eq!(sealed, [seq as u8]);
+                    seqs.push(seq);
+                }
+                frame @ Frame::History { .. } => {
+                    bob.send(frame).await.unwrap();
+                    pages += 1;
+                }
+                f => panic!("expected an ENC or HIS frame, got {f:?}"),
+            }
+        }
+        assert_eq!(seqs, (11..=120).collect::<Vec<_>>());
+        assert_eq!(pages, 3);
+    }
+
+    /// The bytes kept across every room are limited, and the rooms that went the longest without a message go first.
+    #[test]
+    fn history_limit_test() {
+        let mut history = History::new(3 * size(&[0; 10]));
+        history.keep("a".to_owned(), 1, vec![0; 10]);
+        history.keep("b".to_owned(), 2, vec![0; 10]);
+        history.keep("a".to_owned(), 3, vec![0; 10]);
+        assert_eq!(history.bytes, 3 * size(&[0; 10]));
+
+        // #b had its last message before #a did.
+        history.keep("c".to_owned(), 4, vec![0; 10]);
+        let mut rooms: Vec<_> = history.rooms.keys().cloned().collect();
+        rooms.sort();
+        assert_eq!(rooms, ["a", "c"]);
+        assert_eq!(history.bytes, 3 * size(&[0; 10]));
+
+        // Even empty messages count.
+        for seq in 5..100 {
+            history.keep(format!("room{seq}"), seq, vec![]);
+        }
+        assert!(history.bytes <= history.max_bytes);
+        assert!(history.rooms.len() < 10);
+    }
+}
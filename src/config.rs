@@ -0,0 +1,247 @@
+use {
+    crate::theme::ThemeConfig,
+    chrono::format::{Item, StrftimeItems},
+    clap::Parser,
+    serde::Deserialize,
+    std::{collections::HashMap, path::PathBuf},
+};
+
+/// ### Command line arguments
+///
+/// Anything not given here falls back to the config file, and then to asking on stdin.
+#[derive(Parser, Debug, Default)]
+#[command(version, about = "A terminal chat client", long_about = None)]
+pub struct Args {
+    /// The username to chat as.
+    #[arg(short, long)]
+    pub user: Option<String>,
+
+    /// The server to connect to. Either the name of a profile in the config file, or an address.
+    #[arg(short, long)]
+    pub server: Option<String>,
+
+    /// The port of the server. Overrides the port of a profile.
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// The config file to use instead of the default one.
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// A file with the passphrase of the message history in it, so it isn't asked for. An empty file keeps no history.
+    #[arg(long)]
+    pub history_passphrase_file: Option<PathBuf>,
+}
+
+/// ### Config
+///
+/// The contents of the TOML config file.
+///
+/// ```toml
+/// user = "alice"
+/// default_server = "work"
+/// time_format = "%H:%M:%S"
+/// history = 500
+///
+/// [theme]
+/// preset = "light"
+///
+/// [servers.work]
+/// host = "chat.example.com"
+/// port = 42530
+/// ```
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    /// The username to use when the profile doesn't have one.
+    pub user: Option<String>,
+    /// The profile to connect to when `--server` isn't given.
+    pub default_server: Option<String>,
+    /// How the time a message was sent is shown, in `strftime` syntax. `message::DEFAULT_TIME_FORMAT` if not given.
+    pub time_format: Option<String>,
+    /// How many messages are reloaded into each room from the history. `history::DEFAULT_HISTORY` if not given, and 0 keeps no history at all.
+    pub history: Option<usize>,
+    /// The colors of the terminal. See `theme::ThemeConfig`.
+    pub theme: ThemeConfig,
+    /// The server profiles, by name.
+    pub servers: HashMap<String, Profile>,
+}
+
+/// A named server in the config file.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub host: String,
+    pub port: Option<u16>,
+    /// The username to use on this server. Overrides the top level `user`.
+    pub user: Option<String>,
+}
+
+/// Where to connect to and who to connect as, once the arguments and config have been combined.
+///
+/// Any field is `None` if neither the arguments nor the config had it.
+#[derive(Debug, Default, PartialEq)]
+pub struct Settings {
+    pub user: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+}
+
+impl Config {
+    /// The default location of the config file: `config.toml` in the app's config directory.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|d| d.join("chat_app").join("config.toml"))
+    }
+
+    /// Loads the config from a file. A missing file is treated as an empty config.
+    pub fn load(path: &std::path::Path) -> crate::prelude::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(s) => Ok(toml::from_str(&s)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Combines the command line arguments with the config. The arguments always win.
+    pub fn resolve(&self, args: &Args) -> Settings {
+        // `--server` can name a profile. Without it, use the default profile.
+        let name = args.server.as_ref().or(self.default_server.as_ref());
+        let profile = name.and_then(|n| self.servers.get(n));
+
+        let (host, port) = match (profile, &args.server) {
+            (Some(p), _) => (Some(p.host.clone()), args.port.or(p.port)),
+            (None, host) => (host.clone(), args.port),
+        };
+
+        let user = args
+            .user
+            .clone()
+            .or_else(|| profile.and_then(|p| p.user.clone()))
+            .or_else(|| self.user.clone());
+
+        Settings { user, host, port }
+    }
+}
+
+/// Reads the passphrase of the message history from a file, without the line break at the end of it.
+pub fn read_passphrase(path: &std::path::Path) -> std::io::Result<String> {
+    let s = std::fs::read_to_string(path)?;
+    let s = s.strip_suffix('\n').unwrap_or(&s);
+    Ok(s.strip_suffix('\r').unwrap_or(s).to_owned())
+}
+
+/// Checks that a time format from the config is one chrono understands, since it panics on bad ones when formatting.
+pub fn valid_time_format(format: &str) -> bool {
+    !StrftimeItems::new(format).any(|item| item == Item::Error)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{read_passphrase, valid_time_format, Args, Config, Settings},
+        crate::theme::ThemeConfig,
+        clap::Parser,
+    };
+
+    const CONFIG: &str = r#"
+        user = "alice"
+        default_server = "work"
+
+        [servers.work]
+        host = "chat.example.com"
+        port = 4000
+
+        [servers.home]
+        host = "192.168.1.20"
+        user = "ally"
+    "#;
+
+    #[test]
+    fn resolve_test() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+
+        // Nothing given, so the default profile is used.
+        assert_eq!(
+            config.resolve(&Args::default()),
+            Settings {
+                user: Some("alice".to_owned()),
+                host: Some("chat.example.com".to_owned()),
+                port: Some(4000),
+            }
+        );
+
+        // A named profile, with the port and user overridden.
+        let args = Args {
+            server: Some("home".to_owned()),
+            port: Some(5000),
+            user: Some("bob".to_owned()),
+            ..Args::default()
+        };
+        assert_eq!(
+            config.resolve(&args),
+            Settings {
+                user: Some("bob".to_owned()),
+                host: Some("192.168.1.20".to_owned()),
+                port: Some(5000),
+            }
+        );
+
+        // A profile's own user beats the top level one.
+        let args = Args {
+            server: Some("home".to_owned()),
+            ..Args::default()
+        };
+        assert_eq!(config.resolve(&args).user, Some("ally".to_owned()));
+
+        // Anything that isn't a profile is an address.
+        let args = Args {
+            server: Some("example.org".to_owned()),
+            port: Some(6000),
+            ..Args::default()
+        };
+        let settings = config.resolve(&args);
+        assert_eq!(settings.host, Some("example.org".to_owned()));
+        assert_eq!(settings.port, Some(6000));
+    }
+
+    #[test]
+    fn time_format_test() {
+        let config: Config = toml::from_str(r#"time_format = "%d/%m %H:%M""#).unwrap();
+        assert_eq!(config.time_format.as_deref(), Some("%d/%m %H:%M"));
+        assert_eq!(config.history, None);
+        assert!(valid_time_format("%d/%m %H:%M"));
+        assert!(!valid_time_format("%H:%"));
+        assert!(!valid_time_format("%Q"));
+    }
+
+    #[test]
+    fn theme_test() {
+        let config: Config =
+            toml::from_str("[theme]\npreset = \"light\"\ntext = \"black\"").unwrap();
+        assert_eq!(config.theme.preset.as_deref(), Some("light"));
+        assert_eq!(config.theme.text.as_deref(), Some("black"));
+        assert_eq!(Config::default().theme, ThemeConfig::default());
+    }
+
+    #[test]
+    fn passphrase_test() {
+        let args = Args::parse_from(["chat_app", "--history-passphrase-file", "secret.txt"]);
+        assert_eq!(
+            args.history_passphrase_file.as_deref(),
+            Some(std::path::Path::new("secret.txt"))
+        );
+
+        // Only the line break at the end is left off, spaces are part of it.
+        let path = std::env::temp_dir().join(format!("chat_app_passphrase_{}", std::process::id()));
+        std::fs::write(&path, " open sesame\r\n").unwrap();
+        assert_eq!(read_passphrase(&path).unwrap(), " open sesame");
+        std::fs::write(&path, "").unwrap();
+        assert_eq!(read_passphrase(&path).unwrap(), "");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn empty_test() {
+        let config = Config::default();
+        assert_eq!(config.resolve(&Args::default()), Settings::default());
+    }
+}
@@ -0,0 +1,309 @@
+use {
+    openssl::{
+        error::ErrorStack,
+        hash::MessageDigest,
+        pkey::{PKey, Private, Public},
+        rsa::{Padding, Rsa},
+        sign::{Signer, Verifier},
+        symm::{decrypt_aead, encrypt_aead, Cipher},
+    },
+    std::{error::Error, fmt::Display},
+};
+
+/// The size of the group key in bytes.
+pub const GROUP_KEY_SIZE: usize = 32;
+/// The size of a long-term identity key, the server's or a user's, in bits.
+pub const IDENTITY_SIZE: u32 = 3072;
+/// The size of the random nonce put in front of every sealed message.
+pub const NONCE_SIZE: usize = 12;
+/// The size of the authentication tag put behind every sealed message.
+pub const TAG_SIZE: usize = 16;
+/// How many rounds of PBKDF2 a passphrase goes through to make a key.
+pub const PASSPHRASE_ROUNDS: usize = 600_000;
+
+/// An error from encrypting or decrypting a message.
+#[derive(Debug)]
+pub enum CryptoError {
+    /// The sealed message is too short to hold a nonce and a tag.
+    Truncated,
+    /// The authentication tag did not match, so the message or its header was tampered with (or sealed with another key).
+    Authentication,
+    /// Any other error coming from OpenSSL.
+    Ssl(ErrorStack),
+}
+
+impl Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "the encrypted message is truncated"),
+            Self::Authentication => write!(f, "the encrypted message failed authentication"),
+            Self::Ssl(_) => write!(f, "OpenSSL error"),
+        }
+    }
+}
+
+impl Error for CryptoError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Ssl(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ErrorStack> for CryptoError {
+    fn from(e: ErrorStack) -> Self {
+        Self::Ssl(e)
+    }
+}
+
+/// ### Group Key
+///
+/// The symmetrical key shared by every client connected to a server.
+///
+/// The server creates one when it starts, and hands it to each client wrapped with that client's public RSA key.
+/// Messages are encrypted and decrypted with it, so the server never has to give away its own keys.
+#[derive(Clone)]
+pub struct GroupKey {
+    key: [u8; GROUP_KEY_SIZE],
+}
+
+impl GroupKey {
+    /// Generates a new random group key.
+    pub fn generate() -> Result<Self, ErrorStack> {
+        let mut key = [0u8; GROUP_KEY_SIZE];
+        openssl::rand::rand_bytes(&mut key)?;
+        Ok(Self { key })
+    }
+
+    /// Derives a key from a passphrase and a random salt with PBKDF2-HMAC-SHA256, e.g. to encrypt something kept on disk.
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> Result<Self, ErrorStack> {
+        let mut key = [0u8; GROUP_KEY_SIZE];
+        openssl::pkcs5::pbkdf2_hmac(
+            passphrase.as_bytes(),
+            salt,
+            PASSPHRASE_ROUNDS,
+            MessageDigest::sha256(),
+            &mut key,
+        )?;
+        Ok(Self { key })
+    }
+
+    /// Encrypts the group key with a client's public RSA key so it can be sent over the wire.
+    pub fn wrap(&self, rsa: &Rsa<Public>) -> Result<Vec<u8>, ErrorStack> {
+        let mut t = vec![0u8; rsa.size() as usize];
+        let len = rsa.public_encrypt(&self.key, &mut t, Padding::PKCS1_OAEP)?;
+        t.truncate(len);
+        Ok(t)
+    }
+
+    /// Decrypts a group key that was wrapped with our public RSA key.
+    ///
+    /// Returns `None` if the decrypted key is not the right size.
+    pub fn unwrap(rsa: &Rsa<Private>, wrapped: &[u8]) -> Result<Option<Self>, ErrorStack> {
+        let mut t = vec![0u8; rsa.size() as usize];
+        let len = rsa.private_decrypt(wrapped, &mut t, Padding::PKCS1_OAEP)?;
+        Ok(t[0..len].try_into().ok().map(|key| Self { key }))
+    }
+
+    /// The length of a sealed message whose plaintext is `len` bytes long.
+    pub fn sealed_len(len: usize) -> usize {
+        NONCE_SIZE + len + TAG_SIZE
+    }
+
+    /// Encrypts a message with the group key using AES-256-GCM.
+    ///
+    /// Parameters:
+    /// ```text
+    ///     aad: &[u8] // Data that is authenticated but not encrypted, usually the frame header.
+    ///     data: &[u8] // The message to encrypt.
+    /// ```
+    /// Returns the random nonce, followed by the ciphertext, followed by the authentication tag.
+    pub fn seal(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        openssl::rand::rand_bytes(&mut nonce)?;
+
+        let mut tag = [0u8; TAG_SIZE];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            aad,
+            data,
+            &mut tag,
+        )?;
+        Ok([&nonce[..], &ciphertext, &tag].concat())
+    }
+
+    /// Decrypts a message sealed with `seal`, checking it and `aad` against the authentication tag.
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if sealed.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(CryptoError::Truncated);
+        }
+        let (nonce, rest) = sealed.split_at(NONCE_SIZE);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(nonce),
+            aad,
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| CryptoError::Authentication)
+    }
+}
+
+/// ### Identity
+///
+/// A long-term RSA key. Unlike the group key it never changes, so others can remember it and know they are talking to the same server, or the same user.
+///
+/// During the handshake the server proves it holds its key by signing the client's public key together with the wrapped group key.
+/// Users send their own public key in the handshake, and sign every direct message they send with it.
+pub struct Identity {
+    key: PKey<Private>,
+}
+
+impl Identity {
+    /// Generates a new identity key.
+    pub fn generate() -> Result<Self, ErrorStack> {
+        Ok(Self {
+            key: PKey::from_rsa(Rsa::generate(IDENTITY_SIZE)?)?,
+        })
+    }
+
+    /// Loads an identity key from PEM.
+    pub fn from_pem(pem: &[u8]) -> Result<Self, ErrorStack> {
+        Ok(Self {
+            key: PKey::private_key_from_pem(pem)?,
+        })
+    }
+
+    /// Writes the identity key out as PEM.
+    pub fn to_pem(&self) -> Result<Vec<u8>, ErrorStack> {
+        self.key.private_key_to_pem_pkcs8()
+    }
+
+    /// The public half of the identity key, DER-encoded. This is what clients pin.
+    pub fn public_key(&self) -> Result<Vec<u8>, ErrorStack> {
+        self.key.public_key_to_der()
+    }
+
+    /// The identity key as an RSA key, to decrypt what was encrypted with its public half.
+    pub fn rsa(&self) -> Result<Rsa<Private>, ErrorStack> {
+        self.key.rsa()
+    }
+
+    /// Signs `data` with the identity key using RSA-PSS over SHA-256.
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.set_rsa_padding(Padding::PKCS1_PSS)?;
+        signer.update(data)?;
+        signer.sign_to_vec()
+    }
+}
+
+/// Checks a signature made by `Identity::sign` against the DER-encoded public key of the identity.
+pub fn verify(public_key: &[u8], data: &[u8], signature: &[u8]) -> Result<bool, ErrorStack> {
+    let key = PKey::<Public>::public_key_from_der(public_key)?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+    verifier.set_rsa_padding(Padding::PKCS1_PSS)?;
+    verifier.update(data)?;
+    verifier.verify(signature)
+}
+
+/// The fingerprint of a DER-encoded public key, in the same `SHA256:<base64>` form ssh uses.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let hash = openssl::sha::sha256(public_key);
+    let b64 = openssl::base64::encode_block(&hash);
+    format!("SHA256:{}", b64.trim_end_matches('='))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{fingerprint, verify, CryptoError, GroupKey, Identity, NONCE_SIZE},
+        openssl::rsa::Rsa,
+    };
+
+    #[test]
+    fn wrap_test() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let pub_rsa = Rsa::public_key_from_der(&rsa.public_key_to_der().unwrap()).unwrap();
+
+        let key = GroupKey::generate().unwrap();
+        let wrapped = key.wrap(&pub_rsa).unwrap();
+        let unwrapped = GroupKey::unwrap(&rsa, &wrapped).unwrap().unwrap();
+        assert_eq!(key.key, unwrapped.key);
+
+        let sealed = key.seal(b"ENC", b"Hello there!").unwrap();
+        assert_eq!(sealed.len(), GroupKey::sealed_len(12));
+        assert_eq!(unwrapped.open(b"ENC", &sealed).unwrap(), b"Hello there!");
+    }
+
+    #[test]
+    fn nonce_test() {
+        let key = GroupKey::generate().unwrap();
+        let a = key.seal(b"ENC", b"Hello there!").unwrap();
+        let b = key.seal(b"ENC", b"Hello there!").unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn tamper_test() {
+        let key = GroupKey::generate().unwrap();
+        let mut sealed = key.seal(b"ENC", b"Hello there!").unwrap();
+
+        // A different header should fail authentication.
+        assert!(matches!(
+            key.open(b"PRV", &sealed),
+            Err(CryptoError::Authentication)
+        ));
+
+        // So should a flipped bit in the ciphertext.
+        sealed[NONCE_SIZE] ^= 1;
+        assert!(matches!(
+            key.open(b"ENC", &sealed),
+            Err(CryptoError::Authentication)
+        ));
+
+        assert!(matches!(
+            key.open(b"ENC", &sealed[..NONCE_SIZE]),
+            Err(CryptoError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn passphrase_test() {
+        let key = GroupKey::from_passphrase("hunter2", b"salt").unwrap();
+        assert_eq!(
+            key.key,
+            GroupKey::from_passphrase("hunter2", b"salt").unwrap().key
+        );
+        assert_ne!(
+            key.key,
+            GroupKey::from_passphrase("hunter3", b"salt").unwrap().key
+        );
+        assert_ne!(
+            key.key,
+            GroupKey::from_passphrase("hunter2", b"pepper").unwrap().key
+        );
+    }
+
+    #[test]
+    fn identity_test() {
+        let id = Identity::generate().unwrap();
+        let public_key = id.public_key().unwrap();
+        let sig = id.sign(b"Hello there!").unwrap();
+        assert!(verify(&public_key, b"Hello there!", &sig).unwrap());
+        assert!(!verify(&public_key, b"Hello there?", &sig).unwrap());
+
+        // The key should survive a trip through PEM, and keep its fingerprint.
+        let loaded = Identity::from_pem(&id.to_pem().unwrap()).unwrap();
+        let fp = fingerprint(&loaded.public_key().unwrap());
+        assert_eq!(fp, fingerprint(&public_key));
+        assert!(fp.starts_with("SHA256:"));
+        assert_eq!(fp.len(), 7 + 43);
+    }
+}
@@ -0,0 +1,71 @@
+use {
+    crate::{
+        message::{Message, Target},
+        prelude::ConnectionError,
+    },
+    chat_app::protocol::Presence,
+    std::time::Duration,
+};
+
+/// ### Command
+///
+/// Something the user asked for in the terminal, sent to the `sender_loop` to be carried out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Send a message with this payload to a room or a user. Actions are shown as `* user payload`.
+    Send {
+        to: Target,
+        payload: String,
+        action: bool,
+    },
+    /// Join a room, and start recieving the messages sent to it.
+    Join(String),
+    /// Leave a room.
+    Leave(String),
+    /// Change the username.
+    Nick(String),
+    /// Ask the server who is online.
+    Who,
+    /// Let the room or the user know we are typing to them.
+    Typing(Target),
+    /// Ask the server for the messages in a room from before the sequence number `before`, or for the newest ones if it is 0.
+    History { room: String, before: u64 },
+}
+
+/// ### Connection State
+///
+/// Where the `sender_loop` is with its connection to the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Connecting to the server and doing the handshake.
+    Connecting,
+    /// The handshake is done, messages can be sent.
+    ///
+    /// `restarted` is true if the server restarted since we were last connected to it, so its sequence numbers started again.
+    Connected { restarted: bool },
+    /// The connection was lost. Another attempt will be made after `retry_in`.
+    Disconnected { reason: String, retry_in: Duration },
+}
+
+/// ### Network Event
+///
+/// Something that happened on the network side, sent from the `sender_loop` to the terminal to be shown to the user.
+#[derive(Debug)]
+pub enum NetworkEvent {
+    /// A message recieved from the server.
+    Message(Message),
+    /// The connection to the server changed state.
+    State(ConnectionState),
+    /// Something went wrong that is worth telling the user about, but that does not end the session.
+    Error(ConnectionError),
+    /// A line from the client itself, not from any user.
+    Notice(String),
+    /// The server accepted our username.
+    Nick(String),
+    /// The usernames of everyone online, once connected and in answer to `Command::Who`.
+    Users(Vec<String>),
+    /// Someone came online, went offline or changed their username.
+    Presence(Presence),
+    /// Someone is typing in a conversation: a room, or a direct conversation with them.
+    Typing { user: String, conversation: Target },
+}
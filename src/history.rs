@@ -0,0 +1,299 @@
+use {
+    crate::{
+        address::Address,
+        message::{Message, Target},
+        prelude::create_private,
+    },
+    chat_app::crypto::{CryptoError, GroupKey},
+    std::{
+        error::Error,
+        fmt::Display,
+        io::Write,
+        path::{Path, PathBuf},
+    },
+};
+
+/// How many messages are reloaded into a room when the config doesn't say otherwise.
+pub const DEFAULT_HISTORY: usize = 100;
+/// The size of the random salt the passphrase is mixed with.
+const SALT_SIZE: usize = 16;
+/// What is sealed into the key file, to tell whether the passphrase is right.
+const CHECK: &[u8] = b"chat_app history";
+
+/// An error from reading or writing the history.
+#[derive(Debug)]
+pub enum HistoryError {
+    /// Reading or writing a file failed.
+    Io(std::io::Error),
+    /// Encrypting or decrypting failed.
+    Crypto(CryptoError),
+    /// The passphrase is not the one the history was made with.
+    WrongPassphrase,
+}
+
+impl Display for HistoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(_) => write!(f, "Could not read or write the message history"),
+            Self::Crypto(_) => write!(f, "Could not encrypt or decrypt the message history"),
+            Self::WrongPassphrase => write!(f, "Wrong passphrase for the message history"),
+        }
+    }
+}
+
+impl Error for HistoryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Crypto(e) => Some(e),
+            Self::WrongPassphrase => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for HistoryError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<CryptoError> for HistoryError {
+    fn from(e: CryptoError) -> Self {
+        Self::Crypto(e)
+    }
+}
+
+impl From<openssl::error::ErrorStack> for HistoryError {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        Self::Crypto(CryptoError::Ssl(e))
+    }
+}
+
+/// ### History
+///
+/// The messages of every room and direct conversation on a server, kept on disk encrypted with a key made from the user's passphrase.
+///
+/// Each server has a directory of its own, with a `key` file holding the salt and a check value sealed with the key,
+/// and a file for each room or conversation that messages are appended to, each one sealed on its own.
+/// Only the last ones are ever reloaded, so the older ones are let go of once there are twice as many as that.
+/// The names of the server and the rooms are hex encoded, so they make safe file names.
+pub struct History {
+    dir: PathBuf,
+    key: GroupKey,
+    /// How many messages are reloaded into a room when it is opened.
+    reload: usize,
+}
+
+impl History {
+    /// The default location of the history: `history` in the app's data directory.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::data_dir().map(|d| d.join("chat_app").join("history"))
+    }
+
+    /// Opens the history of `server` in `root`, creating it if there isn't one yet.
+    ///
+    /// Parameters:
+    /// ```text
+    /// root: &Path // The directory with the history of every server
+    /// server: &Address // The server the history is for
+    /// passphrase: &str // The passphrase the history is encrypted with
+    /// reload: usize // How many messages are reloaded into a room when it is opened
+    /// ```
+    /// Returns `HistoryError::WrongPassphrase` if the history was made with another passphrase.
+    pub fn open(
+        root: &Path,
+        server: &Address,
+        passphrase: &str,
+        reload: usize,
+    ) -> Result<Self, HistoryError> {
+        let dir = root.join(hex(&server.to_string()));
+        let key_path = dir.join("key");
+        match std::fs::read(&key_path) {
+            Ok(contents) if contents.len() > SALT_SIZE => {
+                let (salt, check) = contents.split_at(SALT_SIZE);
+                let key = GroupKey::from_passphrase(passphrase, salt)?;
+                match key.open(b"key", check) {
+                    Ok(c) if c == CHECK => Ok(Self { dir, key, reload }),
+                    _ => Err(HistoryError::WrongPassphrase),
+                }
+            }
+            Ok(_) => Err(HistoryError::Crypto(CryptoError::Truncated)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut salt = [0u8; SALT_SIZE];
+                openssl::rand::rand_bytes(&mut salt)?;
+                let key = GroupKey::from_passphrase(passphrase, &salt)?;
+                std::fs::create_dir_all(&dir)?;
+                let check = key.seal(b"key", CHECK)?;
+                create_private(&key_path)?.write_all(&[&salt[..], &check].concat())?;
+                Ok(Self { dir, key, reload })
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Appends a message to the history of the room or conversation `target`.
+    pub fn append(&self, target: &Target, msg: &Message) -> Result<(), HistoryError> {
+        let json = serde_json::to_vec(msg).map_err(std::io::Error::from)?;
+        let sealed = self.key.seal(target.to_string().as_bytes(), &json)?;
+
+        let path = self.path(target);
+        let contents = match std::fs::read(&path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                create_private(&path)?;
+                vec![]
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let (records, end) = records(&contents);
+
+        // Start again with only the messages that would be reloaded, swapping the new file in once it is all written.
+        if records.len() >= 2 * self.reload {
+            let tmp = path.with_extension("tmp");
+            _ = std::fs::remove_file(&tmp);
+            let kept = &records[records.len() - self.reload..];
+            let mut file = create_private(&tmp)?;
+            file.write_all(&kept.iter().flat_map(|r| record(r)).collect::<Vec<_>>())?;
+            file.write_all(&record(&sealed))?;
+            std::fs::rename(&tmp, &path)?;
+            return Ok(());
+        }
+
+        // A crash can cut off the record being written. Anything after the last whole one is dropped, so it can't swallow the start of this one.
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path)?;
+        if end < contents.len() {
+            file.set_len(end as u64)?;
+        }
+        file.write_all(&record(&sealed))?;
+        Ok(())
+    }
+
+    /// The last messages in the history of the room or conversation `target`, oldest first.
+    ///
+    /// Records that can't be opened, like one cut off halfway, are skipped.
+    pub fn load(&self, target: &Target) -> Result<Vec<Message>, HistoryError> {
+        let contents = match std::fs::read(self.path(target)) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        // Only as many as are needed are opened, from the newest.
+        let aad = target.to_string();
+        let mut msgs: Vec<Message> = records(&contents)
+            .0
+            .iter()
+            .rev()
+            .filter_map(|sealed| self.key.open(aad.as_bytes(), sealed).ok())
+            .filter_map(|m| serde_json::from_slice(&m).ok())
+            .take(self.reload)
+            .collect();
+        msgs.reverse();
+        Ok(msgs)
+    }
+
+    fn path(&self, target: &Target) -> PathBuf {
+        self.dir.join(hex(&target.to_string()))
+    }
+}
+
+/// Splits the contents of a history file into its records, each one a sealed message.
+///
+/// Returns them, and where the last whole one ends. Anything after that was cut off halfway through being written.
+fn records(contents: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut records = Vec::new();
+    let mut end = 0;
+    while let Some(len) = contents.get(end..end + 4) {
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        let Some(sealed) = contents.get(end + 4..end + 4 + len) else {
+            break;
+        };
+        records.push(sealed);
+        end += 4 + len;
+    }
+    (records, end)
+}
+
+/// A sealed message as it is written to a history file: its length, then the message.
+fn record(sealed: &[u8]) -> Vec<u8> {
+    [&(sealed.len() as u32).to_be_bytes()[..], sealed].concat()
+}
+
+fn hex(s: &str) -> String {
+    s.bytes().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{records, History, HistoryError},
+        crate::{
+            address::Address,
+            message::{Message, Target},
+        },
+    };
+
+    #[test]
+    fn history_test() {
+        let root = std::env::temp_dir().join(format!("chat_app_history_{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&root);
+        let server = Address::parse("example.com").unwrap();
+        let general = Target::Room("general".to_owned());
+        let bob = Target::User("bob".to_owned());
+
+        let history = History::open(&root, &server, "hunter2", 2).unwrap();
+        assert!(history.load(&general).unwrap().is_empty());
+        for payload in ["one", "two", "three"] {
+            let msg = Message::new("alice", "general", payload);
+            history.append(&general, &msg).unwrap();
+        }
+        history
+            .append(&bob, &Message::direct("alice", "bob", "Psst"))
+            .unwrap();
+
+        // Only the last messages come back, and only with the right passphrase.
+        let history = History::open(&root, &server, "hunter2", 2).unwrap();
+        let payloads: Vec<_> = history
+            .load(&general)
+            .unwrap()
+            .iter()
+            .map(|m| m.to_string())
+            .collect();
+        assert_eq!(payloads, ["two", "three"]);
+        assert_eq!(history.load(&bob).unwrap().len(), 1);
+        assert!(matches!(
+            History::open(&root, &server, "hunter3", 2),
+            Err(HistoryError::WrongPassphrase)
+        ));
+
+        // Nothing is kept in the clear, and a cut off record is skipped.
+        let path = history.path(&general);
+        let contents = std::fs::read(&path).unwrap();
+        assert!(!contents.windows(5).any(|w| w == b"three"));
+        std::fs::write(&path, &contents[..contents.len() - 1]).unwrap();
+        assert_eq!(history.load(&general).unwrap().len(), 2);
+
+        // What is appended after it isn't lost along with it.
+        let payloads = |history: &History| -> Vec<String> {
+            let msgs = history.load(&general).unwrap();
+            msgs.iter().map(|m| m.to_string()).collect()
+        };
+        history
+            .append(&general, &Message::new("alice", "general", "four"))
+            .unwrap();
+        assert_eq!(payloads(&history), ["two", "four"]);
+
+        // Only twice as many messages as are reloaded are kept.
+        for n in 5..20 {
+            let msg = Message::new("alice", "general", &n.to_string());
+            history.append(&general, &msg).unwrap();
+        }
+        assert_eq!(payloads(&history), ["18", "19"]);
+        let contents = std::fs::read(&path).unwrap();
+        let (kept, end) = records(&contents);
+        assert!(kept.len() <= 4);
+        assert_eq!(end, contents.len());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// What we know about a server's identity key.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HostStatus {
+    /// The fingerprint matches the one we remembered.
+    Known,
+    /// We have never connected to this server before.
+    New,
+    /// The server presented a different key than last time. Someone may be impersonating it.
+    Mismatch { expected: String },
+}
+
+/// ### Known Hosts
+///
+/// The fingerprints of the identity keys of every server we have connected to, kept in a file much like ssh's `known_hosts`.
+///
+/// Each line of the file is a host, a space, and the fingerprint of its key. Blank lines and lines starting with `#` are ignored.
+///
+/// The keys of the users we send direct messages to are remembered the same way, in a file of their own, as `user@host`.
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: HashMap<String, String>,
+}
+
+impl KnownHosts {
+    /// The default location of the file: `known_hosts` in the app's config directory.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|d| d.join("chat_app").join("known_hosts"))
+    }
+
+    /// The default location of the file the keys of other users are kept in: `known_users` in the app's config directory.
+    pub fn users_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|d| d.join("chat_app").join("known_users"))
+    }
+
+    /// Loads the known hosts from a file. A missing file is treated as empty.
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let hosts = contents
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .filter_map(|l| l.split_once(' '))
+            .map(|(host, fp)| (host.to_owned(), fp.trim().to_owned()))
+            .collect();
+        Ok(Self {
+            path: path.to_owned(),
+            hosts,
+        })
+    }
+
+    /// Checks a fingerprint against the one remembered for the host.
+    pub fn check(&self, host: &str, fingerprint: &str) -> HostStatus {
+        match self.hosts.get(host) {
+            Some(fp) if fp == fingerprint => HostStatus::Known,
+            Some(fp) => HostStatus::Mismatch {
+                expected: fp.to_owned(),
+            },
+            None => HostStatus::New,
+        }
+    }
+
+    /// Remembers the fingerprint for a new host and appends it to the file.
+    pub fn add(&mut self, host: &str, fingerprint: &str) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{host} {fingerprint}")?;
+        self.hosts.insert(host.to_owned(), fingerprint.to_owned());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HostStatus, KnownHosts};
+
+    #[test]
+    fn tofu_test() {
+        let path =
+            std::env::temp_dir().join(format!("chat_app_known_hosts_{}", std::process::id()));
+        _ = std::fs::remove_file(&path);
+
+        let mut hosts = KnownHosts::load(&path).unwrap();
+        assert_eq!(
+            hosts.check("example.com:42530", "SHA256:abc"),
+            HostStatus::New
+        );
+        hosts.add("example.com:42530", "SHA256:abc").unwrap();
+
+        // It should still be known after loading the file again.
+        let hosts = KnownHosts::load(&path).unwrap();
+        assert_eq!(
+            hosts.check("example.com:42530", "SHA256:abc"),
+            HostStatus::Known
+        );
+        assert_eq!(
+            hosts.check("example.com:42530", "SHA256:xyz"),
+            HostStatus::Mismatch {
+                expected: "SHA256:abc".to_owned()
+            }
+        );
+        assert_eq!(
+            hosts.check("example.org:42530", "SHA256:abc"),
+            HostStatus::New
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,6 @@
+//! Code shared between the `chat_app` client and the `chat_server` binary.
+
+pub mod crypto;
+pub mod message;
+pub mod prelude;
+pub mod protocol;
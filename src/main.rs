@@ -1,30 +1,127 @@
-use {prelude::*, tokio::task::*};
+use {
+    address::Address,
+    chat_app::{
+        message::{self, DEFAULT_TIME_FORMAT},
+        prelude,
+        protocol::{valid_user, MAX_USER_LEN},
+    },
+    clap::Parser,
+    config::{read_passphrase, valid_time_format, Args, Config},
+    history::{History, DEFAULT_HISTORY},
+    prelude::*,
+    std::io::IsTerminal,
+    theme::Theme,
+    tokio::task::*,
+};
 
-mod message;
-mod prelude;
+mod address;
+mod config;
+mod event;
+mod history;
+mod known_hosts;
+mod markup;
+mod message_list;
+mod rooms;
+mod roster;
 mod sender;
+mod slash;
 mod terminal;
+mod theme;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Get the alleged username of the user
-    let mut s = String::new();
-    println!("Enter your username:");
-    std::io::stdin().read_line(&mut s)?;
-    let user = s.trim().to_owned();
-    s.clear();
+    // Combine the command line arguments with the config file.
+    let args = Args::parse();
+    let config = match args.config.clone().or_else(Config::default_path) {
+        Some(path) => Config::load(&path)
+            .map_err(|e| format!("Could not load the config file {}: {e}", path.display()))?,
+        None => Config::default(),
+    };
+    let settings = config.resolve(&args);
+    let time_format = config
+        .time_format
+        .unwrap_or_else(|| DEFAULT_TIME_FORMAT.to_owned());
+    if !valid_time_format(&time_format) {
+        eprintln!("'{time_format}' is not a valid time format. See https://docs.rs/chrono/latest/chrono/format/strftime for the syntax.");
+        std::process::exit(1);
+    }
+    let theme = match Theme::from_config(&config.theme) {
+        Ok(theme) => theme,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
 
-    // Get the target ip for the server.
-    println!("And what is the ip of the server you will be joining?");
-    std::io::stdin().read_line(&mut s)?;
-    let ip = s.trim().to_owned();
+    // Get the alleged username of the user, if it wasn't given.
+    let user = match settings.user {
+        Some(user) => user,
+        None => prompt("Enter your username:")?,
+    };
+    if !valid_user(&user) {
+        eprintln!("'{user}' is not a valid username. It must be 1 to {MAX_USER_LEN} characters long, without any spaces.");
+        std::process::exit(1);
+    }
+
+    // Get the target ip for the server, if it wasn't given. A port given on its own wins over one in the address.
+    let host = match settings.host {
+        Some(host) => host,
+        None => prompt("And what is the ip of the server you will be joining?")?,
+    };
+    let address = Address::parse(&host).map(|mut a| {
+        a.port = settings.port.unwrap_or(a.port);
+        a
+    });
+    let address = match address {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{}", error_chain(&e));
+            std::process::exit(1);
+        }
+    };
+
+    // Open the history of the server, unless the user doesn't want one.
+    let reload = config.history.unwrap_or(DEFAULT_HISTORY);
+    let history = match (reload, History::default_path()) {
+        (1.., Some(root)) => {
+            let passphrase = match &args.history_passphrase_file {
+                Some(path) => read_passphrase(path).map_err(|e| {
+                    format!("Could not read the passphrase file {}: {e}", path.display())
+                })?,
+                // With no one at a terminal to ask, go without.
+                None if !std::io::stdin().is_terminal() => String::new(),
+                None => rpassword::prompt_password(
+                    "Passphrase for your message history (leave it empty to not keep one):",
+                )?,
+            };
+            match passphrase.as_str() {
+                "" => None,
+                p => match History::open(&root, &address, p, reload) {
+                    Ok(h) => Some(h),
+                    Err(e) => {
+                        eprintln!("{}", error_chain(&e));
+                        std::process::exit(1);
+                    }
+                },
+            }
+        }
+        _ => None,
+    };
 
     // Spawn terminal thread
     spawn(async {
-        if let Err(e) = terminal::terminal_loop(user, ip).await {
-            println!("{}", e.message());
+        if let Err(e) = terminal::terminal_loop(user, address, time_format, history, theme).await {
+            eprintln!("{}", error_chain(&e));
         }
     })
     .await?;
     Ok(())
 }
+
+/// Asks a question on stdout and reads the answer from stdin.
+fn prompt(question: &str) -> Result<String> {
+    let mut s = String::new();
+    println!("{question}");
+    std::io::stdin().read_line(&mut s)?;
+    Ok(s.trim().to_owned())
+}
@@ -0,0 +1,335 @@
+use {
+    crate::theme::Theme,
+    ratatui::{
+        style::{Modifier, Style},
+        text::Span,
+    },
+};
+
+/// The characters a `\` stops from being read as markup.
+const ESCAPED: &str = "\\*_`[";
+/// The characters left off the end of a bare link, as they are more likely to be punctuation around it.
+const LINK_END: &str = ".,;:!?)'\"";
+
+/// ### Part
+///
+/// A piece of a message payload, after its markup has been read.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Part {
+    /// Text, with the style of each piece of it. It is wrapped like any other.
+    Text(Vec<Span<'static>>),
+    /// A fenced code block, shown as it is, in a box. `lang` is whatever followed the opening fence.
+    Code { lang: String, lines: Vec<String> },
+}
+
+/// # Parse
+///
+/// Parameters:
+/// ```text
+/// text: &str // A message payload
+/// theme: &Theme // The colors of the text, code and links
+/// ```
+/// Reads the little markup messages can have:
+/// ```text
+/// *bold* or **bold**
+/// _italic_
+/// `code`
+/// [text](https://example.com) and bare https://example.com links
+/// ``` on a line of its own, to start and end a block of code
+/// ```
+/// Markers only count at the edges of words, so `snake_case_names` and `2*3*4` are left alone, and a `\` in front of one stops it counting.
+///
+/// Returns the text and the code blocks in it, in order.
+pub fn parse(text: &str, theme: &Theme) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut prose: Vec<&str> = Vec::new();
+    let mut lines = text.split('\n');
+    while let Some(line) = lines.next() {
+        let Some(lang) = line.trim_start().strip_prefix("```") else {
+            prose.push(line);
+            continue;
+        };
+        if !prose.is_empty() {
+            parts.push(Part::Text(inline(&prose.join("\n"), theme)));
+            prose.clear();
+        }
+        // A block that is never closed runs to the end of the message.
+        let lines = lines
+            .by_ref()
+            .take_while(|l| !l.trim_start().starts_with("```"))
+            .map(str::to_owned)
+            .collect();
+        parts.push(Part::Code {
+            lang: lang.trim().to_owned(),
+            lines,
+        });
+    }
+    if !prose.is_empty() {
+        parts.push(Part::Text(inline(&prose.join("\n"), theme)));
+    }
+    parts
+}
+
+/// Reads the markup in a piece of text that isn't in a code block.
+fn inline(text: &str, theme: &Theme) -> Vec<Span<'static>> {
+    let mut spans = Vec::new();
+    push_inline(text, Style::default().fg(theme.text), theme, &mut spans);
+    spans
+}
+
+/// # Push Inline
+///
+/// Parameters:
+/// ```text
+/// text: &str // The text to read
+/// style: Style // The style it is in. Markup in it adds to it
+/// theme: &Theme // The colors of code and links
+/// spans: &mut Vec<Span> // Where the styled pieces of the text go
+/// ```
+/// Reads the markup in the text, and adds it to `spans` in the right styles. Bold and italic text is read again for markup inside it.
+fn push_inline(text: &str, style: Style, theme: &Theme, spans: &mut Vec<Span<'static>>) {
+    let mut plain = String::new();
+    let mut prev = None;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        // The formatted text, its style, and how much of `rest` it took up.
+        let mut run = None;
+        let after_word = prev.is_some_and(char::is_alphanumeric);
+        match c {
+            '\\' => {
+                if let Some(e) = rest[1..].chars().next().filter(|e| ESCAPED.contains(*e)) {
+                    plain.push(e);
+                    prev = Some(e);
+                    rest = &rest[1 + e.len_utf8()..];
+                    continue;
+                }
+            }
+            '`' => {
+                if let Some(end) = rest[1..].find('`').filter(|&end| end > 0) {
+                    run = Some((&rest[1..=end], None, end + 2));
+                }
+            }
+            '*' | '_' if !after_word => {
+                let delim = if rest.starts_with("**") {
+                    "**"
+                } else {
+                    &rest[..1]
+                };
+                let modifier = match c {
+                    '*' => Modifier::BOLD,
+                    _ => Modifier::ITALIC,
+                };
+                if let Some(end) = closing(&rest[delim.len()..], delim) {
+                    let inner = &rest[delim.len()..delim.len() + end];
+                    run = Some((
+                        inner,
+                        Some(style.add_modifier(modifier)),
+                        delim.len() * 2 + end,
+                    ));
+                }
+            }
+            '[' => {
+                if let Some((label, url, len)) = link(rest) {
+                    flush(&mut plain, style, spans);
+                    push_inline(label, link_style(style, theme), theme, spans);
+                    if label != url {
+                        spans.push(Span::styled(
+                            format!(" ({url})"),
+                            style.add_modifier(Modifier::DIM),
+                        ));
+                    }
+                    prev = Some(')');
+                    rest = &rest[len..];
+                    continue;
+                }
+            }
+            'h' if !after_word && (rest.starts_with("https://") || rest.starts_with("http://")) => {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                let url = rest[..end].trim_end_matches(|c| LINK_END.contains(c));
+                flush(&mut plain, style, spans);
+                spans.push(Span::styled(url.to_owned(), link_style(style, theme)));
+                prev = url.chars().last();
+                rest = &rest[url.len()..];
+                continue;
+            }
+            _ => {}
+        }
+
+        match run {
+            // Bold and italic text can have more markup in it.
+            Some((inner, Some(inner_style), len)) => {
+                flush(&mut plain, style, spans);
+                push_inline(inner, inner_style, theme, spans);
+                prev = Some(c);
+                rest = &rest[len..];
+            }
+            // Code is shown as it is.
+            Some((inner, None, len)) => {
+                flush(&mut plain, style, spans);
+                spans.push(Span::styled(inner.to_owned(), style.fg(theme.code)));
+                prev = Some(c);
+                rest = &rest[len..];
+            }
+            None => {
+                plain.push(c);
+                prev = Some(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    flush(&mut plain, style, spans);
+}
+
+/// The style of a link in text in `style`.
+fn link_style(style: Style, theme: &Theme) -> Style {
+    style.fg(theme.link).add_modifier(Modifier::UNDERLINED)
+}
+
+/// Adds the plain text read so far to `spans`, if there is any.
+fn flush(plain: &mut String, style: Style, spans: &mut Vec<Span<'static>>) {
+    if !plain.is_empty() {
+        spans.push(Span::styled(std::mem::take(plain), style));
+    }
+}
+
+/// Finds the `delim` closing formatting that started just before `rest`.
+///
+/// The formatted text can't start or end with a space, and the closing `delim` can't be followed by a letter or digit.
+///
+/// Returns where in `rest` it is, or None if the formatting is never closed.
+fn closing(rest: &str, delim: &str) -> Option<usize> {
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    rest.match_indices(delim).map(|(i, _)| i).find(|&i| {
+        i > 0
+            && !rest[..i].ends_with(char::is_whitespace)
+            && !rest[i + delim.len()..].starts_with(char::is_alphanumeric)
+    })
+}
+
+/// Reads a `[label](url)` link at the start of `rest`.
+///
+/// Returns the label, the url, and how much of `rest` the link took up.
+fn link(rest: &str) -> Option<(&str, &str, usize)> {
+    let label_end = rest.find("](")?;
+    let label = &rest[1..label_end];
+    let url_end = label_end + 2 + rest[label_end + 2..].find(')')?;
+    let url = &rest[label_end + 2..url_end];
+    let valid = !label.is_empty()
+        && !label.contains('\n')
+        && !url.is_empty()
+        && !url.contains(char::is_whitespace);
+    valid.then_some((label, url, url_end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{parse, Part},
+        crate::theme::Theme,
+        ratatui::style::Modifier,
+    };
+
+    /// The text of each piece of formatted text, and whether it is bold, italic, code and a link.
+    fn pieces(text: &str) -> Vec<(String, bool, bool, bool, bool)> {
+        let theme = Theme::default();
+        let [Part::Text(spans)] = &parse(text, &theme)[..] else {
+            panic!("{text:?} isn't only text");
+        };
+        spans
+            .iter()
+            .map(|s| {
+                let m = s.style.add_modifier;
+                (
+                    s.content.to_string(),
+                    m.contains(Modifier::BOLD),
+                    m.contains(Modifier::ITALIC),
+                    s.style.fg == Some(theme.code),
+                    m.contains(Modifier::UNDERLINED),
+                )
+            })
+            .collect()
+    }
+
+    /// The text of each piece of formatted text.
+    fn texts(text: &str) -> Vec<String> {
+        pieces(text).into_iter().map(|p| p.0).collect()
+    }
+
+    #[test]
+    fn inline_test() {
+        let p = |s: &str, b, i, c, l| (s.to_owned(), b, i, c, l);
+        assert_eq!(
+            pieces("a *bold* _it_ `co*de*` b"),
+            [
+                p("a ", false, false, false, false),
+                p("bold", true, false, false, false),
+                p(" ", false, false, false, false),
+                p("it", false, true, false, false),
+                p(" ", false, false, false, false),
+                p("co*de*", false, false, true, false),
+                p(" b", false, false, false, false),
+            ]
+        );
+        assert_eq!(pieces("**_both_**"), [p("both", true, true, false, false)]);
+
+        // Markers inside words, around spaces, or escaped, are only text.
+        assert_eq!(texts("snake_case_name"), ["snake_case_name"]);
+        assert_eq!(texts("2*3*4 and * not * this"), ["2*3*4 and * not * this"]);
+        assert_eq!(texts(r"\*not bold\* \_ a\b"), [r"*not bold* _ a\b"]);
+        assert_eq!(texts("*never closed"), ["*never closed"]);
+    }
+
+    #[test]
+    fn link_test() {
+        let [_, (label, .., true), (url, ..), _] =
+            &pieces("see [the docs](https://docs.rs) now")[..]
+        else {
+            panic!("the link wasn't read");
+        };
+        assert_eq!(label, "the docs");
+        assert_eq!(url, " (https://docs.rs)");
+
+        // Bare links end before the punctuation after them.
+        let pieces = pieces("at https://example.com/a_b_c.");
+        assert_eq!(pieces[1].0, "https://example.com/a_b_c");
+        assert!(pieces[1].4);
+        assert_eq!(pieces[2].0, ".");
+        assert_eq!(texts("[not a link] (x)"), ["[not a link] (x)"]);
+    }
+
+    #[test]
+    fn code_block_test() {
+        let parts = parse(
+            "Look:\n```rust\nfn main() {\n    *x* = 1;\n}\n```\nNeat.",
+            &Theme::default(),
+        );
+        assert_eq!(parts.len(), 3);
+        assert_eq!(
+            parts[1],
+            Part::Code {
+                lang: "rust".to_owned(),
+                lines: vec![
+                    "fn main() {".to_owned(),
+                    "    *x* = 1;".to_owned(),
+                    "}".to_owned()
+                ]
+            }
+        );
+        let Part::Text(after) = &parts[2] else {
+            panic!("expected text after the block");
+        };
+        assert_eq!(after[0].content, "Neat.");
+
+        // A block that isn't closed runs to the end.
+        assert_eq!(
+            parse("```\nlet x;", &Theme::default()),
+            [Part::Code {
+                lang: String::new(),
+                lines: vec!["let x;".to_owned()]
+            }]
+        );
+        assert_eq!(parse("", &Theme::default()), [Part::Text(vec![])]);
+    }
+}
@@ -1,60 +1,246 @@
-use serde::{Deserialize, Serialize};
+use {
+    chrono::{DateTime, Local, Utc},
+    serde::{Deserialize, Serialize},
+};
+
+/// How the time a message was sent is shown when the config doesn't say otherwise. See `chrono::format::strftime` for the syntax.
+pub const DEFAULT_TIME_FORMAT: &str = "%H:%M";
 
 /// ### Message
 ///
 /// A structure that represents a message sent by a user.
 ///
-/// Each Message contains the name of the user who sent it, the time it was sent, and the payload (contents of the message).
+/// Each Message contains the name of the user who sent it, the room or user it was sent to, the time it was sent, and the payload (contents of the message).
+///
+/// The time is kept in UTC, and sent as RFC 3339, so messages can be ordered and shown in the reader's own timezone.
+///
+/// Every message has a unique id made by the client that sent it, so it can be told apart from copies of itself.
+/// The server gives each message a sequence number as it passes it on, which is filled in when it is recieved.
 ///
 /// Derives Serialize and Deserialize for easy transmission.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
+    id: String,
+    /// The sequence number given by the server. None until the message has been through it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
     from: String,
-    time: String,
+    #[serde(flatten)]
+    to: Target,
+    time: DateTime<Utc>,
     payload: String,
+    /// Whether the payload is an action, like `/me waves`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    action: bool,
 }
 
 impl Message {
     /// Constructs a new Message.
     ///
     /// Parameters:
-    /// ```
+    /// ```text
     ///     user: &str // The string representing the user who sent the message.
+    ///     room: &str // The room the message is sent to.
     ///     content: &str // The string representing the contents of the message.
     /// ```
     /// Returns a new Message structure
-    pub fn new(user: &str, payload: &str) -> Self {
-        let now = chrono::offset::Local::now()
-            .format("%H:%M | %Y %d %m")
-            .to_string();
+    pub fn new(user: &str, room: &str, payload: &str) -> Self {
+        Self::sent_to(user, Target::Room(room.to_owned()), payload)
+    }
+
+    /// Constructs a new direct Message, sent to the user `to` alone.
+    pub fn direct(user: &str, to: &str, payload: &str) -> Self {
+        Self::sent_to(user, Target::User(to.to_owned()), payload)
+    }
+
+    /// Constructs a new Message sent to a room or a user.
+    pub fn sent_to(user: &str, to: Target, payload: &str) -> Self {
         Self {
+            id: new_id(),
+            seq: None,
             from: user.to_owned(),
-            time: now,
+            to,
+            time: Utc::now(),
             payload: payload.to_owned(),
+            action: false,
+        }
+    }
+
+    /// Constructs a new action Message, shown as `* user payload`.
+    pub fn action(user: &str, to: Target, payload: &str) -> Self {
+        Self {
+            action: true,
+            ..Self::sent_to(user, to, payload)
+        }
+    }
+
+    /// The unique id given to the message by the client that sent it.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The sequence number given to the message by the server, if it has been through it.
+    pub fn seq(&self) -> Option<u64> {
+        self.seq
+    }
+
+    /// Sets the sequence number the server gave the message. Whatever the sender put in it is not to be trusted.
+    pub fn set_seq(&mut self, seq: u64) {
+        self.seq = Some(seq);
+    }
+
+    /// The name of the user who sent the message.
+    pub fn from(&self) -> &str {
+        &self.from
+    }
+
+    /// The room or user the message was sent to.
+    pub fn to(&self) -> &Target {
+        &self.to
+    }
+
+    /// When the message was sent.
+    pub fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+
+    /// The conversation the message belongs in for the user `me`: its room, or the other user for direct messages.
+    pub fn conversation(&self, me: &str) -> Target {
+        match &self.to {
+            Target::User(to) if self.from == me => Target::User(to.clone()),
+            Target::User(_) => Target::User(self.from.clone()),
+            room => room.clone(),
         }
     }
 
-    /// Get's the length of the header of the message (The username and time)
-    pub fn get_header(&self) -> String {
-        format!("{} @ {}: ", self.from, self.time)
+    /// The time the message was sent in the local timezone, in `time_format`.
+    pub fn local_time(&self, time_format: &str) -> String {
+        self.time
+            .with_timezone(&Local)
+            .format(time_format)
+            .to_string()
+    }
+}
+
+/// Makes a new random message id: 16 random bytes, in hex.
+fn new_id() -> String {
+    let mut id = [0u8; 16];
+    // This only fails if OpenSSL can't seed its random number generator, and then nothing else would work either.
+    openssl::rand::rand_bytes(&mut id).expect("could not generate a message id");
+    id.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// ### Target
+///
+/// Where a message is sent: a room, or a single user.
+///
+/// It is flattened into the message, as either a `room` or a `user` field.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    Room(String),
+    User(String),
+}
+
+/// Rooms are shown as `#room`, and users as `@user`.
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Room(room) => write!(f, "#{room}"),
+            Self::User(user) => write!(f, "@{user}"),
+        }
     }
 }
 
 /// Implement Display for Message
 impl std::fmt::Display for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.payload)
+        if self.action {
+            write!(f, "* {} {}", self.from, self.payload)
+        } else {
+            write!(f, "{}", self.payload)
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use {
+        super::{Message, Target},
+        chrono::{DateTime, Utc},
+    };
+
+    #[test]
+    fn target_test() {
+        let m = Message::new("alice", "general", "Hi all");
+        let j = serde_json::to_value(&m).unwrap();
+        assert_eq!(j["room"], "general");
+        assert_eq!(m.conversation("bob"), Target::Room("general".to_owned()));
+
+        // Direct messages belong with the other user, on both ends.
+        let m = Message::direct("alice", "bob", "Hi Bob");
+        let j = serde_json::to_value(&m).unwrap();
+        assert_eq!(j["user"], "bob");
+        let m: Message = serde_json::from_value(j).unwrap();
+        assert_eq!(m.conversation("alice"), Target::User("bob".to_owned()));
+        assert_eq!(m.conversation("bob"), Target::User("alice".to_owned()));
+        assert_eq!(m.to().to_string(), "@bob");
+    }
+
+    #[test]
+    fn action_test() {
+        let m = Message::action("alice", Target::Room("general".to_owned()), "waves");
+        assert_eq!(m.to_string(), "* alice waves");
+        let j = serde_json::to_string(&m).unwrap();
+        let m: Message = serde_json::from_str(&j).unwrap();
+        assert_eq!(m.to_string(), "* alice waves");
+
+        // Plain messages don't carry the flag at all.
+        let m = Message::new("alice", "general", "waves");
+        assert!(!serde_json::to_string(&m).unwrap().contains("action"));
+        assert_eq!(m.to_string(), "waves");
+    }
+
+    #[test]
+    fn id_test() {
+        let a = Message::new("alice", "general", "Hi");
+        let b = Message::new("alice", "general", "Hi");
+        assert_eq!(a.id().len(), 32);
+        assert_ne!(a.id(), b.id());
+
+        // The sequence number is only there once the server has given one.
+        let mut j = serde_json::to_value(&a).unwrap();
+        assert!(j.get("seq").is_none());
+        let mut m: Message = serde_json::from_value(j.clone()).unwrap();
+        assert_eq!(m.id(), a.id());
+        assert_eq!(m.seq(), None);
+        m.set_seq(7);
+        assert_eq!(m.seq(), Some(7));
+
+        j["seq"] = 3.into();
+        assert_eq!(serde_json::from_value::<Message>(j).unwrap().seq(), Some(3));
+    }
+
     #[test]
     fn json_test() {
-        let m = crate::message::Message::new("Aeskul", "Hello there!");
-        let j = serde_json::to_string_pretty(&m).unwrap();
+        let m = crate::message::Message::new("Aeskul", "general", "Hello there!");
+        let j = serde_json::to_value(&m).unwrap();
         println!("{j}");
 
-        assert_eq!(m.time.len(), 16);
+        // The time is sent as RFC 3339, in UTC, and survives the trip.
+        let time = j["time"].as_str().unwrap();
+        assert!(time.ends_with('Z'));
+        assert_eq!(DateTime::parse_from_rfc3339(time).unwrap(), m.time());
+        let back: Message = serde_json::from_value(j).unwrap();
+        assert_eq!(back.time(), m.time());
+
+        // Times from other timezones are read as the same instant.
+        let j = serde_json::json!({"id": "1", "from": "bob", "room": "general", "time": "2024-03-01T01:30:00+02:00", "payload": "Hi"});
+        let m: Message = serde_json::from_value(j).unwrap();
+        assert_eq!(
+            m.time(),
+            "2024-02-29T23:30:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        assert!(m.local_time("%Y").starts_with("202"));
     }
 }
@@ -0,0 +1,558 @@
+use {
+    crate::{
+        markup::{self, Part},
+        rooms::{Entry, NoticeKind},
+        theme::Theme,
+    },
+    chrono::{Local, NaiveDate},
+    ratatui::{
+        buffer::Buffer,
+        layout::{Alignment, Rect},
+        style::{Modifier, Style},
+        text::{Line, Span},
+        widgets::{
+            block::{Position, Title},
+            Block, Paragraph, StatefulWidget, Widget,
+        },
+    },
+    unicode_width::{UnicodeWidthChar, UnicodeWidthStr},
+};
+
+/// How many lines the mouse wheel scrolls at a time.
+pub const WHEEL_LINES: usize = 3;
+
+/// ### Message View
+///
+/// Where the messages of a room are scrolled to, kept between draws.
+///
+/// It follows the newest messages until the user scrolls up, and then stays on the lines they are reading as more arrive below.
+#[derive(Debug, Default)]
+pub struct MessageView {
+    /// The first line shown, counted from the top, or None to follow the newest messages.
+    offset: Option<usize>,
+    /// Whether messages arrived below the lines shown since the user scrolled up.
+    unseen: bool,
+    /// How many lines could be shown at the last draw. A page is this many lines.
+    height: usize,
+    /// How many lines there were at the last draw, at the width they were wrapped to then.
+    total: usize,
+}
+
+impl MessageView {
+    /// Scrolls up by `lines`, stopping at the first line.
+    pub fn scroll_up(&mut self, lines: usize) {
+        self.offset = Some(self.top().saturating_sub(lines));
+    }
+
+    /// Scrolls down by `lines`. Reaching the bottom follows the newest messages again.
+    pub fn scroll_down(&mut self, lines: usize) {
+        let top = self.top() + lines;
+        if top >= self.last_page() {
+            self.offset = None;
+            self.unseen = false;
+        } else {
+            self.offset = Some(top);
+        }
+    }
+
+    /// Scrolls up by the height of the view.
+    pub fn page_up(&mut self) {
+        self.scroll_up(self.height.max(1));
+    }
+
+    /// Scrolls down by the height of the view.
+    pub fn page_down(&mut self) {
+        self.scroll_down(self.height.max(1));
+    }
+
+    /// Whether the first line is shown.
+    pub fn at_top(&self) -> bool {
+        self.top() == 0
+    }
+
+    /// Notes that a message was added after the others, so the user can be told about it if they have scrolled up.
+    pub fn arrived(&mut self) {
+        self.unseen |= self.offset.is_some();
+    }
+
+    /// The first line of the last page.
+    fn last_page(&self) -> usize {
+        self.total.saturating_sub(self.height)
+    }
+
+    /// The first line shown. The lines may have been rewrapped since the view was scrolled, so it never goes past the last page.
+    fn top(&self) -> usize {
+        self.offset.unwrap_or(usize::MAX).min(self.last_page())
+    }
+}
+
+/// ### Message List
+///
+/// A widget showing the entries of a room, wrapped to its width, scrolled to where its `MessageView` says.
+///
+/// Everything is wrapped again on every draw, so it fits whatever size the terminal is.
+pub struct MessageList<'a> {
+    entries: &'a [Entry],
+    time_format: &'a str,
+    /// The user the messages are shown to. Their own messages stand out from the others.
+    me: &'a str,
+    theme: &'a Theme,
+    block: Option<Block<'a>>,
+}
+
+impl<'a> MessageList<'a> {
+    /// Constructs a new MessageList showing `entries` to the user `me` in the colors of `theme`, with the time messages were sent in `time_format`.
+    pub fn new(entries: &'a [Entry], time_format: &'a str, me: &'a str, theme: &'a Theme) -> Self {
+        Self {
+            entries,
+            time_format,
+            me,
+            theme,
+            block: None,
+        }
+    }
+
+    /// Surrounds the list with a block.
+    pub fn block(mut self, block: Block<'a>) -> Self {
+        self.block = Some(block);
+        self
+    }
+}
+
+impl StatefulWidget for MessageList<'_> {
+    type State = MessageView;
+
+    fn render(self, area: Rect, buf: &mut Buffer, view: &mut MessageView) {
+        let inner = self.block.as_ref().map_or(area, |b| b.inner(area));
+        let lines = lines(
+            self.entries,
+            self.time_format,
+            self.me,
+            self.theme,
+            inner.width as usize,
+        );
+        view.height = inner.height as usize;
+        view.total = lines.len();
+
+        let shown: Vec<_> = lines
+            .into_iter()
+            .skip(view.top())
+            .take(view.height)
+            .collect();
+        let mut paragraph = Paragraph::new(shown);
+        if let Some(mut block) = self.block {
+            if view.unseen {
+                block = block.title(
+                    Title::from(" New messages below ↓ ")
+                        .position(Position::Bottom)
+                        .alignment(Alignment::Right),
+                );
+            }
+            paragraph = paragraph.block(block);
+        }
+        paragraph.render(area, buf);
+    }
+}
+
+/// # Lines
+///
+/// Parameters:
+/// ```text
+/// entries: &[Entry] // The entries of a room, in order
+/// time_format: &str // How to show the time a message was sent
+/// me: &str // The user the entries are shown to
+/// theme: &Theme // The colors to show them in
+/// width: usize // The width to wrap them to
+/// ```
+/// Returns the lines the entries are shown as: a header and the text of each one, followed by a blank line,
+/// with a day separator before the first message of each day.
+///
+/// Usernames are shown in their own color, with `me` standing out, and times are in the timestamp color. Notices from the client are in italics.
+/// The markup in messages is shown in its styles, with blocks of code in boxes (see `markup::parse`).
+pub fn lines(
+    entries: &[Entry],
+    time_format: &str,
+    me: &str,
+    theme: &Theme,
+    width: usize,
+) -> Vec<Line<'static>> {
+    let timestamp = Style::default().fg(theme.timestamp);
+    let mut lines = Vec::new();
+    let mut last_day = None;
+    for entry in entries {
+        let (header, body) = match entry {
+            Entry::Message(m) => {
+                let day = m.time().with_timezone(&Local).date_naive();
+                if last_day.replace(day) != Some(day) {
+                    lines.push(Line::from(Span::styled(day_rule(day, width), timestamp)));
+                }
+                let mut name = Style::default()
+                    .fg(theme.user_color(m.from()))
+                    .add_modifier(Modifier::BOLD);
+                if m.from() == me {
+                    name = name.add_modifier(Modifier::REVERSED);
+                }
+                let header = vec![
+                    Span::styled(m.from().to_owned(), name),
+                    Span::styled(format!(" @ {}", m.local_time(time_format)), timestamp),
+                ];
+                (header, markup::parse(&m.to_string(), theme))
+            }
+            Entry::Notice { kind, text } => {
+                let color = match kind {
+                    NoticeKind::Error => theme.error,
+                    NoticeKind::System | NoticeKind::Help => theme.status,
+                };
+                let style = Style::default().fg(color).add_modifier(Modifier::ITALIC);
+                let header = vec![Span::styled(
+                    kind.to_string(),
+                    style.add_modifier(Modifier::BOLD),
+                )];
+                (
+                    header,
+                    vec![Part::Text(vec![Span::styled(text.clone(), style)])],
+                )
+            }
+        };
+        lines.extend(wrap(&header, width));
+        for part in body {
+            match part {
+                Part::Text(spans) => lines.extend(wrap(&spans, width)),
+                Part::Code { lang, lines: code } => {
+                    lines.extend(code_box(&lang, &code, theme, width))
+                }
+            }
+        }
+        lines.push(Line::default());
+    }
+    lines
+}
+
+/// # Wrap
+///
+/// Parameters:
+/// ```text
+/// spans: &[Span] // The styled text to wrap. Newlines in it always start a new line
+/// width: usize // The most columns a line can take up
+/// ```
+/// Splits the text into lines no wider than `width` on the screen, breaking them between words, and keeping the style of every character.
+/// Words too wide for a line of their own are broken between characters.
+pub fn wrap(spans: &[Span], width: usize) -> Vec<Line<'static>> {
+    let chars: Vec<_> = spans
+        .iter()
+        .flat_map(|s| s.content.chars().map(|c| (c, s.style)))
+        .collect();
+
+    let mut lines = Vec::new();
+    for paragraph in chars.split(|&(c, _)| c == '\n') {
+        let mut line = Vec::new();
+        let mut line_width = 0;
+        for word in paragraph.split_inclusive(|&(c, _)| c == ' ') {
+            // Move the word to the next line if it doesn't fit on this one.
+            let word_width: usize = word
+                .iter()
+                .filter(|&&(c, _)| c != ' ')
+                .map(|&(c, _)| c.width().unwrap_or(0))
+                .sum();
+            if line_width > 0 && line_width + word_width > width {
+                lines.push(to_line(&line));
+                line.clear();
+                line_width = 0;
+            }
+            for &(c, style) in word {
+                let w = c.width().unwrap_or(0);
+                if line_width > 0 && line_width + w > width {
+                    if c == ' ' {
+                        continue; // Spaces at the end of a line aren't shown.
+                    }
+                    lines.push(to_line(&line));
+                    line.clear();
+                    line_width = 0;
+                }
+                line.push((c, style));
+                line_width += w;
+            }
+        }
+        lines.push(to_line(&line));
+    }
+    lines
+}
+
+/// # Code Box
+///
+/// Parameters:
+/// ```text
+/// lang: &str // The language of the code, shown at the top of the box if it fits
+/// code: &[String] // The lines of code
+/// theme: &Theme // The colors of the code and the box
+/// width: usize // The most columns the box can take up
+/// ```
+/// Returns the lines of a box around the code, as wide as its longest line. Lines too long for it are broken wherever they reach the edge.
+fn code_box(lang: &str, code: &[String], theme: &Theme, width: usize) -> Vec<Line<'static>> {
+    let border = Style::default().fg(theme.timestamp);
+    // Leave room for the sides, and a space inside each of them.
+    let max = width.saturating_sub(4).max(1);
+    let rows: Vec<_> = code.iter().flat_map(|l| break_line(l, max)).collect();
+    let inner = rows.iter().map(|r| r.width()).max().unwrap_or(0).min(max);
+
+    let title = format!(" {lang} ");
+    let top = match lang.is_empty() || title.width() > inner + 1 {
+        true => format!("┌{}┐", "─".repeat(inner + 2)),
+        false => format!("┌─{title}{}┐", "─".repeat(inner + 1 - title.width())),
+    };
+    let mut lines = vec![Line::from(Span::styled(top, border))];
+    for row in rows {
+        let pad = " ".repeat(inner.saturating_sub(row.width()));
+        lines.push(Line::from(vec![
+            Span::styled("│ ", border),
+            Span::styled(row, Style::default().fg(theme.code)),
+            Span::raw(pad),
+            Span::styled(" │", border),
+        ]));
+    }
+    lines.push(Line::from(Span::styled(
+        format!("└{}┘", "─".repeat(inner + 2)),
+        border,
+    )));
+    lines
+}
+
+/// Breaks a line of code into pieces no wider than `width`, between any two characters. Tabs are turned into spaces first.
+fn break_line(line: &str, width: usize) -> Vec<String> {
+    let mut pieces = vec![String::new()];
+    let mut piece_width = 0;
+    for c in line.replace('\t', "    ").chars() {
+        let w = c.width().unwrap_or(0);
+        if piece_width > 0 && piece_width + w > width {
+            pieces.push(String::new());
+            piece_width = 0;
+        }
+        pieces.last_mut().unwrap().push(c);
+        piece_width += w;
+    }
+    pieces
+}
+
+/// Makes a Line out of styled characters, with a Span for each run of them in the same style. Spaces at the end are left out.
+fn to_line(chars: &[(char, Style)]) -> Line<'static> {
+    let end = chars
+        .iter()
+        .rposition(|&(c, _)| c != ' ')
+        .map_or(0, |i| i + 1);
+    let spans: Vec<_> = chars[..end]
+        .chunk_by(|a, b| a.1 == b.1)
+        .map(|run| Span::styled(run.iter().map(|&(c, _)| c).collect::<String>(), run[0].1))
+        .collect();
+    Line::from(spans)
+}
+
+/// A line across the messages with the date on it, like `──── Thursday 29 February 2024 ────`.
+fn day_rule(day: NaiveDate, width: usize) -> String {
+    let date = format!(" {} ", day.format("%A %-d %B %Y"));
+    let rule = "─".repeat(width.saturating_sub(date.width()) / 2);
+    format!("{rule}{date}{rule}")
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{lines, wrap, MessageList, MessageView},
+        crate::{
+            rooms::{Entry, NoticeKind},
+            theme::Theme,
+        },
+        chat_app::message::Message,
+        ratatui::{
+            buffer::Buffer,
+            layout::Rect,
+            style::{Color, Modifier, Style},
+            text::{Line, Span},
+            widgets::{Block, Borders, StatefulWidget},
+        },
+    };
+
+    /// The text of a line, without its styles.
+    fn text(line: &Line) -> String {
+        line.spans.iter().map(|s| s.content.as_ref()).collect()
+    }
+
+    /// Wraps unstyled text, and returns the text of each line.
+    fn plain(s: &str, width: usize) -> Vec<String> {
+        wrap(&[Span::raw(s)], width).iter().map(text).collect()
+    }
+
+    /// A message from `from` sent at `time`, given in RFC 3339.
+    fn msg(from: &str, time: &str, payload: &str) -> Entry {
+        let j = serde_json::json!({"id": payload, "from": from, "room": "general", "time": time, "payload": payload});
+        Entry::Message(serde_json::from_value::<Message>(j).unwrap())
+    }
+
+    /// Draws the entries into a buffer `width` by `height`, and returns its rows.
+    fn draw(entries: &[Entry], view: &mut MessageView, width: u16, height: u16) -> Vec<String> {
+        let area = Rect::new(0, 0, width, height);
+        let mut buf = Buffer::empty(area);
+        let theme = Theme::default();
+        let list = MessageList::new(entries, "%H:%M", "alice", &theme)
+            .block(Block::default().borders(Borders::ALL));
+        list.render(area, &mut buf, view);
+        (0..height)
+            .map(|y| (0..width).map(|x| buf.get(x, y).symbol.clone()).collect())
+            .collect()
+    }
+
+    fn notice(text: &str) -> Entry {
+        Entry::Notice {
+            kind: NoticeKind::System,
+            text: text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn wrap_test() {
+        assert_eq!(plain("the quick brown fox", 10), ["the quick", "brown fox"]);
+        assert_eq!(plain("one\n\ntwo", 10), ["one", "", "two"]);
+        assert_eq!(plain("", 10), [""]);
+
+        // Words too long for a line are broken up, and characters are never split.
+        assert_eq!(
+            plain("a supercalifragilistic b", 8),
+            ["a", "supercal", "ifragili", "stic b"]
+        );
+        assert_eq!(plain("héllo wörld", 5), ["héllo", "wörld"]);
+
+        // Wide characters take up two columns.
+        assert_eq!(plain("日本語のテキスト", 6), ["日本語", "のテキ", "スト"]);
+        assert_eq!(plain("日本", 1), ["日", "本"]);
+    }
+
+    #[test]
+    fn day_test() {
+        let entries = [
+            msg("bob", "2024-02-29T12:00:00Z", "one"),
+            msg("bob", "2024-02-29T12:05:00Z", "two"),
+            notice("Connected."),
+            msg("bob", "2024-03-01T12:00:00Z", "three"),
+        ];
+
+        // Only the first message of each day gets a separator.
+        let lines: Vec<_> = lines(&entries, "%H:%M", "alice", &Theme::default(), 40)
+            .iter()
+            .map(text)
+            .collect();
+        let rules: Vec<_> = lines.iter().filter(|l| l.starts_with('─')).collect();
+        assert_eq!(rules.len(), 2);
+        assert!(lines[0].contains("February 2024"));
+        assert!(rules[1].contains("March 2024"));
+        assert!(lines.iter().all(|l| l.chars().count() <= 40));
+    }
+
+    #[test]
+    fn style_test() {
+        let theme = Theme::light();
+        let entries = [
+            msg("bob", "2024-02-29T12:00:00Z", "Hi"),
+            msg("alice", "2024-02-29T12:01:00Z", "Hello"),
+            notice("Connected."),
+        ];
+        let lines = lines(&entries, "%H:%M", "alice", &theme, 40);
+        let bob = &lines[1].spans;
+        assert_eq!(bob[0].content, "bob");
+        assert_eq!(bob[0].style.fg, Some(theme.user_color("bob")));
+        assert_eq!(bob[1].style.fg, Some(theme.timestamp));
+        assert!(!bob[0].style.add_modifier.contains(Modifier::REVERSED));
+
+        // Our own messages stand out, and notices are in italics.
+        let alice = &lines[4].spans;
+        assert_eq!(alice[0].content, "alice");
+        assert!(alice[0].style.add_modifier.contains(Modifier::REVERSED));
+        assert_eq!(text(&lines[8]), "Connected.");
+        assert!(lines[8].spans[0]
+            .style
+            .add_modifier
+            .contains(Modifier::ITALIC));
+
+        // Styles carry over when a line is wrapped.
+        let red = Style::default().fg(Color::Red);
+        let wrapped = wrap(&[Span::raw("plain "), Span::styled("red text", red)], 9);
+        assert_eq!(
+            wrapped.iter().map(text).collect::<Vec<_>>(),
+            ["plain red", "text"]
+        );
+        assert_eq!(wrapped[0].spans[1].style, red);
+        assert_eq!(wrapped[1].spans[0].style, red);
+    }
+
+    #[test]
+    fn code_box_test() {
+        let entries = [msg(
+            "bob",
+            "2024-02-29T12:00:00Z",
+            "Try *this*:\n```rust\nlet x = 1;\n\tx\n```",
+        )];
+        let shown = lines(&entries, "%H:%M", "alice", &Theme::default(), 20);
+        let texts: Vec<_> = shown.iter().map(text).collect();
+        assert_eq!(
+            texts[2..],
+            [
+                "Try this:",
+                "┌─ rust ─────┐",
+                "│ let x = 1; │",
+                "│     x      │",
+                "└────────────┘",
+                "",
+            ]
+        );
+        assert!(shown[2].spans[1]
+            .style
+            .add_modifier
+            .contains(Modifier::BOLD));
+
+        // Lines too long for the box are broken at its edge. The header takes two lines at this width.
+        let long = [msg("bob", "2024-02-29T12:00:00Z", "```\nabcdefghij\n```")];
+        let texts: Vec<_> = lines(&long, "%H:%M", "alice", &Theme::default(), 10)
+            .iter()
+            .map(text)
+            .collect();
+        assert_eq!(
+            texts[3..7],
+            ["┌────────┐", "│ abcdef │", "│ ghij   │", "└────────┘"]
+        );
+    }
+
+    #[test]
+    fn scroll_test() {
+        let entries: Vec<_> = (0..10).map(|i| notice(&format!("line {i}"))).collect();
+        let mut view = MessageView::default();
+
+        // It follows the newest messages, and they are wrapped again when the width changes.
+        let rows = draw(&entries, &mut view, 30, 5);
+        assert!(rows[2].contains("line 9"));
+        let rows = draw(&entries, &mut view, 6, 8);
+        assert!(rows[2].contains("syst") && rows[3].contains("em"));
+        assert!(rows[5].contains('9'));
+        draw(&entries, &mut view, 30, 8);
+        assert!(!view.at_top());
+
+        // Scrolled up, it stays on the same lines when more arrive, and says there are new ones.
+        view.page_up();
+        let before = draw(&entries, &mut view, 30, 8);
+        let mut more = entries.clone();
+        more.push(notice("line 10"));
+        view.arrived();
+        let after = draw(&more, &mut view, 30, 8);
+        assert_eq!(before[..7], after[..7]);
+        assert!(after[7].contains("New messages below"));
+
+        // Scrolling back down to the bottom follows them again.
+        for _ in 0..10 {
+            view.page_down();
+        }
+        let rows = draw(&more, &mut view, 30, 8);
+        assert!(rows[5].contains("line 10"));
+        assert!(!rows[7].contains("New"));
+
+        view.scroll_up(usize::MAX);
+        assert!(view.at_top());
+        assert!(draw(&more, &mut view, 20, 5)[1].contains("system"));
+    }
+}
@@ -1,30 +1,249 @@
-use std::{error::Error, fmt::Display};
+use {
+    crate::{
+        crypto::{CryptoError, Identity},
+        message::Target,
+        protocol::ProtocolError,
+    },
+    std::{error::Error, fmt::Display, io::Write, path::Path},
+};
 
 /// Generic Result type
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-/// An error for connections. All it contains is a String dennoting the error (may expand later).
+/// ### Connection Error
+///
+/// Everything that can go wrong while talking to the other end of a connection.
+///
+/// Each variant keeps the error that caused it (if any), which can be walked with `Error::source`.
 #[derive(Debug)]
-pub struct ConnectionError {
-    message: String,
+pub enum ConnectionError {
+    /// Reading from or writing to the socket or terminal failed.
+    Io(std::io::Error),
+    /// The address of the server could not be understood.
+    InvalidAddress(String),
+    /// The host name of the server could not be resolved.
+    Resolve(String, std::io::Error),
+    /// Connecting to the given address failed.
+    Connect(String, std::io::Error),
+    /// The handshake did not go as expected.
+    Handshake(String),
+    /// The server's identity key is not the one we remembered for this host.
+    HostKeyMismatch {
+        host: String,
+        expected: String,
+        found: String,
+    },
+    /// A user's key is not the one we remembered for them, so direct messages to and from them are refused.
+    /// It is only reported, as the connection to the server carries on.
+    UserKeyMismatch {
+        user: String,
+        expected: String,
+        found: String,
+    },
+    /// Encrypting, decrypting or generating keys failed, in the handshake or with our own keys.
+    Crypto(CryptoError),
+    /// A message sent to a room, or to us by a user, could not be decrypted. Only that message is lost.
+    Undecryptable { from: Target, source: CryptoError },
+    /// The peer sent something that breaks the wire protocol.
+    Protocol(ProtocolError),
+    /// A message could not be serialized or deserialized.
+    Json(serde_json::Error),
+    /// The server refused to do something we asked, for the given reason.
+    Refused(String),
+    /// The channel between the terminal and the sender was closed.
+    ChannelClosed,
+    /// The other end closed the connection.
+    Closed,
 }
 
 impl ConnectionError {
-    pub fn new(s: &str) -> Self {
-        Self {
-            message: s.to_owned(),
+    /// Whether the error could go away by connecting again, like the network dropping or the server restarting.
+    ///
+    /// Errors that mean the server can't be trusted, or that the app itself is shutting down, are not retryable.
+    /// A message someone else sent that can't be decrypted says nothing about the server, so it is.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::Io(_)
+                | Self::Resolve(..)
+                | Self::Connect(..)
+                | Self::Undecryptable { .. }
+                | Self::Protocol(_)
+                | Self::Closed
+        )
+    }
+}
+
+impl Display for ConnectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(_) => write!(f, "I/O error"),
+            Self::InvalidAddress(addr) => write!(
+                f,
+                "'{addr}' is not a valid address. Use host, host:port or [ipv6]:port"
+            ),
+            Self::Resolve(host, _) => write!(f, "Could not resolve {host}"),
+            Self::Connect(addr, _) => write!(f, "Could not connect to {addr}"),
+            Self::Handshake(s) => write!(f, "Handshake failed: {s}"),
+            Self::HostKeyMismatch { host, expected, found } => write!(
+                f,
+                "WARNING: THE IDENTITY OF {host} HAS CHANGED!\n\
+                 Someone could be impersonating the server, so the connection was refused.\n\
+                 Expected fingerprint: {expected}\n\
+                 Presented fingerprint: {found}\n\
+                 If the server's key was changed on purpose, remove the line for {host} from your known_hosts file."
+            ),
+            Self::UserKeyMismatch { user, expected, found } => write!(
+                f,
+                "WARNING: THE KEY OF {user} HAS CHANGED!\n\
+                 Someone could be impersonating them, so direct messages to and from them are refused.\n\
+                 Expected fingerprint: {expected}\n\
+                 Presented fingerprint: {found}\n\
+                 If they changed their key on purpose, remove the line for {user} from your known_users file."
+            ),
+            Self::Crypto(_) => write!(f, "Encryption error"),
+            Self::Undecryptable {
+                from: from @ Target::Room(_),
+                ..
+            } => write!(f, "Could not decrypt a message in {from}"),
+            Self::Undecryptable {
+                from: Target::User(user),
+                ..
+            } => write!(f, "Could not decrypt a message from {user}"),
+            Self::Protocol(_) => write!(f, "Protocol error"),
+            Self::Json(_) => write!(f, "A message could not be (de)serialized"),
+            Self::Refused(reason) => write!(f, "The server refused: {reason}"),
+            Self::ChannelClosed => {
+                write!(f, "The terminal and sender stopped talking to each other")
+            }
+            Self::Closed => write!(f, "The connection was closed by the other end"),
         }
     }
+}
 
-    pub fn message(&self) -> &str {
-        &self.message
+impl Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) | Self::Resolve(_, e) | Self::Connect(_, e) => Some(e),
+            Self::Crypto(e) | Self::Undecryptable { source: e, .. } => Some(e),
+            Self::Protocol(e) => Some(e),
+            Self::Json(e) => Some(e),
+            _ => None,
+        }
     }
 }
 
-impl Display for ConnectionError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "{}", self.message)
+impl From<std::io::Error> for ConnectionError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<CryptoError> for ConnectionError {
+    fn from(e: CryptoError) -> Self {
+        Self::Crypto(e)
+    }
+}
+
+impl From<openssl::error::ErrorStack> for ConnectionError {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        Self::Crypto(CryptoError::Ssl(e))
+    }
+}
+
+impl From<ProtocolError> for ConnectionError {
+    fn from(e: ProtocolError) -> Self {
+        Self::Protocol(e)
+    }
+}
+
+impl From<serde_json::Error> for ConnectionError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for ConnectionError {
+    fn from(_: tokio::sync::mpsc::error::SendError<T>) -> Self {
+        Self::ChannelClosed
+    }
+}
+
+/// Creates a new file only the owner can read, for keys and anything else that should stay private.
+///
+/// Fails if there is already a file at `path`.
+pub fn create_private(path: &Path) -> std::io::Result<std::fs::File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+/// Loads the identity key kept at `path`, generating a new one and saving it there if there is nothing there.
+///
+/// Returns the key, and whether it was just generated.
+pub fn load_identity(path: &Path) -> std::result::Result<(Identity, bool), ConnectionError> {
+    match std::fs::read(path) {
+        Ok(pem) => Ok((Identity::from_pem(&pem)?, false)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let identity = Identity::generate()?;
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+
+            // Only the owner should be able to read the key.
+            create_private(path)?.write_all(&identity.to_pem()?)?;
+
+            Ok((identity, true))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Formats an error along with the chain of errors that caused it, one per line.
+pub fn error_chain(e: &dyn Error) -> String {
+    let mut s = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        s.push_str(&format!("\n  caused by: {e}"));
+        source = e.source();
     }
+    s
 }
 
-impl Error for ConnectionError {}
+#[cfg(test)]
+mod tests {
+    use {
+        super::{error_chain, ConnectionError},
+        crate::{crypto::CryptoError, message::Target},
+        std::error::Error,
+    };
+
+    #[test]
+    fn source_test() {
+        let e = ConnectionError::from(std::io::Error::new(
+            std::io::ErrorKind::ConnectionReset,
+            "reset by peer",
+        ));
+        assert_eq!(e.source().unwrap().to_string(), "reset by peer");
+
+        let e = ConnectionError::from(CryptoError::Authentication);
+        assert_eq!(
+            error_chain(&e),
+            "Encryption error\n  caused by: the encrypted message failed authentication"
+        );
+        assert!(!e.is_retryable());
+
+        // A message that can't be opened is only that message's problem.
+        let e = ConnectionError::Undecryptable {
+            from: Target::Room("general".to_owned()),
+            source: CryptoError::Authentication,
+        };
+        assert_eq!(
+            error_chain(&e),
+            "Could not decrypt a message in #general\n  caused by: the encrypted message failed authentication"
+        );
+        assert!(e.is_retryable());
+    }
+}
@@ -0,0 +1,1000 @@
+use {
+    crate::{
+        crypto::{verify, CryptoError, GroupKey, Identity},
+        message::Target,
+        prelude::ConnectionError,
+    },
+    bytes::{Buf, BufMut, BytesMut},
+    openssl::{
+        pkey::{Private, Public},
+        rsa::Rsa,
+    },
+    std::{error::Error, fmt::Display},
+    tokio_util::codec::{Decoder, Encoder},
+};
+
+/// The size of a frame header: a 3 byte tag followed by a 4 byte big-endian body length.
+pub const HEADER_SIZE: usize = 7;
+/// The room every client joins when it starts.
+pub const DEFAULT_ROOM: &str = "general";
+/// The longest a room name can be, in characters.
+pub const MAX_ROOM_LEN: usize = 32;
+/// The longest a username can be, in characters.
+pub const MAX_USER_LEN: usize = 32;
+/// The most messages the server sends in answer to a single `HIS` frame.
+pub const HISTORY_PAGE: usize = 50;
+
+/// ### Frame
+///
+/// A single unit of the wire protocol spoken between the client and the server.
+///
+/// Every frame is written as a 3 byte ASCII tag, a 4 byte big-endian length, and a body of that length.
+/// Frames with more than one field write each field as a 4 byte big-endian length followed by the field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// `PUB`: The client's public RSA key, DER-encoded. Sent by the client to start the handshake.
+    /// It is the public half of the user's long-term identity key, so it is also the key other users send direct messages to.
+    Pub(Vec<u8>),
+    /// `IDN`: The server's long-term identity key, DER-encoded, and its signature over the client's `PUB` key followed by the wrapped group key.
+    /// Sent by the server just before `PRV`.
+    ///
+    /// `epoch` changes every time the server starts, and with it the sequence numbers start again from 1.
+    Idn {
+        key: Vec<u8>,
+        signature: Vec<u8>,
+        epoch: u64,
+    },
+    /// `PRV`: The group key, wrapped with the client's public RSA key. Sent by the server to finish the handshake.
+    Prv(Vec<u8>),
+    /// `ENC`: The room a message was sent to, its sequence number, and the message sealed with the group key.
+    /// The tag and the room are authenticated along with the message, so the server can't move it to another room.
+    ///
+    /// Clients send it with a sequence number of 0, and the server replaces it with the next one before passing it on.
+    Enc {
+        room: String,
+        seq: u64,
+        sealed: Vec<u8>,
+    },
+    /// `JON`: The client joins a room, and will be sent every `ENC` frame sent to it from now on.
+    Join(String),
+    /// `LEV`: The client leaves a room.
+    Leave(String),
+    /// `NCK`: The client's username. Sent by the client once the handshake is done, and whenever it wants to change it.
+    /// The server sends it back once the name is taken, or an `ERR` if it can't be.
+    Nick(String),
+    /// `KRQ`: The client asks for the public key of a user, to send them a direct message.
+    KeyRequest(String),
+    /// `KEY`: The public RSA key of a user, DER-encoded, in answer to a `KRQ`. The key is empty if the user isn't online.
+    Key { user: String, key: Vec<u8> },
+    /// `DMS`: A direct message, sealed with a key of its own which is wrapped with the recipient's public RSA key,
+    /// along with the sender's public key and its signature over the names, the wrapped key and the sealed message.
+    /// `peer` is the recipient when the client sends it, and the server replaces it with the sender when passing it on.
+    /// Like `ENC`, it is given a sequence number by the server.
+    Direct {
+        peer: String,
+        seq: u64,
+        sender_key: Vec<u8>,
+        wrapped: Vec<u8>,
+        sealed: Vec<u8>,
+        signature: Vec<u8>,
+    },
+    /// `ERR`: The server refused to do something the client asked, and why.
+    Error(String),
+    /// `WHO`: The client asks who is online.
+    Who,
+    /// `USR`: The usernames of everyone online. Sent in answer to a `WHO`, and once the client has taken a username.
+    Users(Vec<String>),
+    /// `PRS`: Someone came online, went offline or changed their username. Sent by the server to every client.
+    Presence(Presence),
+    /// `TYP`: Someone is typing in a room, or to a user. The client sends it with its own name, and the server passes it on
+    /// to everyone else in the room, or to the user, with the name of whoever sent it.
+    Typing { to: Target, from: String },
+    /// `HIS`: The client asks for the messages the server still has for a room it is in, with a sequence number above `after`
+    /// and below `before`, or with no upper limit if `before` is 0. The server sends up to `HISTORY_PAGE` of them as `ENC` frames:
+    /// the oldest if `after` isn't 0, to catch up from there, and the newest otherwise.
+    ///
+    /// If there was more to catch up on, the server then sends the `HIS` frame back with `after` moved up to the last one it sent,
+    /// and the client sends it again for the next page.
+    History {
+        room: String,
+        after: u64,
+        before: u64,
+    },
+}
+
+/// ### Presence
+///
+/// A change to who is online.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Presence {
+    Joined(String),
+    Left(String),
+    Renamed { from: String, to: String },
+}
+
+impl Display for Presence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Joined(user) => write!(f, "{user} joined"),
+            Self::Left(user) => write!(f, "{user} left"),
+            Self::Renamed { from, to } => write!(f, "{from} is now known as {to}"),
+        }
+    }
+}
+
+impl Frame {
+    /// Seals `data` with the group key into an `ENC` frame for `room`, without a sequence number.
+    pub fn seal(key: &GroupKey, room: &str, data: &[u8]) -> Result<Self, CryptoError> {
+        Ok(Self::Enc {
+            room: room.to_owned(),
+            seq: 0,
+            sealed: key.seal(&enc_aad(room), data)?,
+        })
+    }
+
+    /// Opens the fields of an `ENC` frame sealed with `seal`.
+    pub fn open(key: &GroupKey, room: &str, sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        key.open(&enc_aad(room), sealed)
+    }
+
+    /// Seals `data` from `from` so only `to` can open it, and signs it with `from`'s identity key, into a `DMS` frame.
+    ///
+    /// A new key is made for every message and wrapped with `to`'s public key. Both names are authenticated along with the message.
+    pub fn seal_direct(
+        identity: &Identity,
+        public_key: &Rsa<Public>,
+        from: &str,
+        to: &str,
+        data: &[u8],
+    ) -> Result<Self, CryptoError> {
+        let key = GroupKey::generate()?;
+        let wrapped = key.wrap(public_key)?;
+        let sealed = key.seal(&direct_aad(from, to), data)?;
+        Ok(Self::Direct {
+            peer: to.to_owned(),
+            seq: 0,
+            sender_key: identity.public_key()?,
+            signature: identity.sign(&direct_signed(from, to, &wrapped, &sealed))?,
+            wrapped,
+            sealed,
+        })
+    }
+
+    /// Opens the fields of a `DMS` frame sealed with `seal_direct`, with the private key of `to`.
+    ///
+    /// The signature is checked against `sender_key` first. It only proves who sent the message if that key is one we trust to be `from`'s.
+    pub fn open_direct(
+        rsa: &Rsa<Private>,
+        from: &str,
+        to: &str,
+        sender_key: &[u8],
+        wrapped: &[u8],
+        sealed: &[u8],
+        signature: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        if !verify(
+            sender_key,
+            &direct_signed(from, to, wrapped, sealed),
+            signature,
+        )? {
+            return Err(CryptoError::Authentication);
+        }
+        let key = GroupKey::unwrap(rsa, wrapped)?.ok_or(CryptoError::Authentication)?;
+        key.open(&direct_aad(from, to), sealed)
+    }
+
+    /// The 3 byte tag that identifies the type of the frame.
+    pub fn tag(&self) -> &'static [u8; 3] {
+        match self {
+            Self::Pub(_) => b"PUB",
+            Self::Idn { .. } => b"IDN",
+            Self::Prv(_) => b"PRV",
+            Self::Enc { .. } => b"ENC",
+            Self::Join(_) => b"JON",
+            Self::Leave(_) => b"LEV",
+            Self::Nick(_) => b"NCK",
+            Self::KeyRequest(_) => b"KRQ",
+            Self::Key { .. } => b"KEY",
+            Self::Direct { .. } => b"DMS",
+            Self::Error(_) => b"ERR",
+            Self::Who => b"WHO",
+            Self::Users(_) => b"USR",
+            Self::Presence(_) => b"PRS",
+            Self::Typing { .. } => b"TYP",
+            Self::History { .. } => b"HIS",
+        }
+    }
+
+    /// The body of the frame, as it is written on the wire.
+    pub fn body(&self) -> Vec<u8> {
+        match self {
+            Self::Pub(b) | Self::Prv(b) => b.clone(),
+            Self::Idn {
+                key,
+                signature,
+                epoch,
+            } => join_fields(&[key, signature, &epoch.to_be_bytes()]),
+            Self::Enc { room, seq, sealed } => {
+                join_fields(&[room.as_bytes(), &seq.to_be_bytes(), sealed])
+            }
+            Self::Join(s)
+            | Self::Leave(s)
+            | Self::Nick(s)
+            | Self::KeyRequest(s)
+            | Self::Error(s) => s.as_bytes().to_vec(),
+            Self::Key { user, key } => join_fields(&[user.as_bytes(), key]),
+            Self::Direct {
+                peer,
+                seq,
+                sender_key,
+                wrapped,
+                sealed,
+                signature,
+            } => join_fields(&[
+                peer.as_bytes(),
+                &seq.to_be_bytes(),
+                sender_key,
+                wrapped,
+                sealed,
+                signature,
+            ]),
+            Self::Who => vec![],
+            Self::Users(users) => {
+                join_fields(&users.iter().map(|u| u.as_bytes()).collect::<Vec<_>>())
+            }
+            Self::Presence(Presence::Joined(user)) => join_fields(&[b"join", user.as_bytes()]),
+            Self::Presence(Presence::Left(user)) => join_fields(&[b"left", user.as_bytes()]),
+            Self::Presence(Presence::Renamed { from, to }) => {
+                join_fields(&[b"nick", from.as_bytes(), to.as_bytes()])
+            }
+            Self::Typing {
+                to: Target::Room(room),
+                from,
+            } => join_fields(&[b"room", room.as_bytes(), from.as_bytes()]),
+            Self::Typing {
+                to: Target::User(user),
+                from,
+            } => join_fields(&[b"user", user.as_bytes(), from.as_bytes()]),
+            Self::History {
+                room,
+                after,
+                before,
+            } => join_fields(&[room.as_bytes(), &after.to_be_bytes(), &before.to_be_bytes()]),
+        }
+    }
+
+    /// The header of the frame, as it is written on the wire.
+    pub fn header(&self) -> [u8; HEADER_SIZE] {
+        header(self.tag(), self.body().len())
+    }
+
+    /// Builds a frame from its tag and body.
+    fn from_parts(tag: [u8; 3], body: Vec<u8>) -> Result<Self, ProtocolError> {
+        match &tag {
+            b"PUB" => Ok(Self::Pub(body)),
+            b"IDN" => {
+                let [key, signature, epoch] = split_fields(tag, &body)?;
+                Ok(Self::Idn {
+                    key,
+                    signature,
+                    epoch: seq_field(tag, epoch)?,
+                })
+            }
+            b"PRV" => Ok(Self::Prv(body)),
+            b"ENC" => {
+                let [room, seq, sealed] = split_fields(tag, &body)?;
+                Ok(Self::Enc {
+                    room: room_field(tag, room)?,
+                    seq: seq_field(tag, seq)?,
+                    sealed,
+                })
+            }
+            b"JON" => Ok(Self::Join(room_field(tag, body)?)),
+            b"LEV" => Ok(Self::Leave(room_field(tag, body)?)),
+            b"NCK" => Ok(Self::Nick(user_field(tag, body)?)),
+            b"KRQ" => Ok(Self::KeyRequest(user_field(tag, body)?)),
+            b"KEY" => {
+                let [user, key] = split_fields(tag, &body)?;
+                Ok(Self::Key {
+                    user: user_field(tag, user)?,
+                    key,
+                })
+            }
+            b"DMS" => {
+                let [peer, seq, sender_key, wrapped, sealed, signature] = split_fields(tag, &body)?;
+                Ok(Self::Direct {
+                    peer: user_field(tag, peer)?,
+                    seq: seq_field(tag, seq)?,
+                    sender_key,
+                    wrapped,
+                    sealed,
+                    signature,
+                })
+            }
+            b"ERR" => Ok(Self::Error(
+                String::from_utf8(body).map_err(|_| ProtocolError::Malformed(tag))?,
+            )),
+            b"WHO" if body.is_empty() => Ok(Self::Who),
+            b"WHO" => Err(ProtocolError::Malformed(tag)),
+            b"USR" => Ok(Self::Users(
+                split_all(tag, &body)?
+                    .into_iter()
+                    .map(|u| user_field(tag, u))
+                    .collect::<Result<_, _>>()?,
+            )),
+            b"PRS" => {
+                let presence = match split_all(tag, &body)?.as_slice() {
+                    [kind, user] if kind == b"join" => {
+                        Presence::Joined(user_field(tag, user.clone())?)
+                    }
+                    [kind, user] if kind == b"left" => {
+                        Presence::Left(user_field(tag, user.clone())?)
+                    }
+                    [kind, from, to] if kind == b"nick" => Presence::Renamed {
+                        from: user_field(tag, from.clone())?,
+                        to: user_field(tag, to.clone())?,
+                    },
+                    _ => return Err(ProtocolError::Malformed(tag)),
+                };
+                Ok(Self::Presence(presence))
+            }
+            b"TYP" => {
+                let [kind, to, from] = split_fields(tag, &body)?;
+                let to = match &kind[..] {
+                    b"room" => Target::Room(room_field(tag, to)?),
+                    b"user" => Target::User(user_field(tag, to)?),
+                    _ => return Err(ProtocolError::Malformed(tag)),
+                };
+                Ok(Self::Typing {
+                    to,
+                    from: user_field(tag, from)?,
+                })
+            }
+            b"HIS" => {
+                let [room, after, before] = split_fields(tag, &body)?;
+                Ok(Self::History {
+                    room: room_field(tag, room)?,
+                    after: seq_field(tag, after)?,
+                    before: seq_field(tag, before)?,
+                })
+            }
+            _ => Err(ProtocolError::UnknownFrame(tag)),
+        }
+    }
+}
+
+/// Checks that a room name is one that can be joined: 1 to `MAX_ROOM_LEN` characters, with no whitespace or control characters.
+pub fn valid_room(name: &str) -> bool {
+    valid_name(name, MAX_ROOM_LEN)
+}
+
+/// Checks that a username is one that can be used: 1 to `MAX_USER_LEN` characters, with no whitespace or control characters.
+pub fn valid_user(name: &str) -> bool {
+    valid_name(name, MAX_USER_LEN)
+}
+
+fn valid_name(name: &str, max: usize) -> bool {
+    let len = name.chars().count();
+    (1..=max).contains(&len) && !name.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Reads a room name out of a field, rejecting the frame if it isn't a valid one.
+fn room_field(tag: [u8; 3], field: Vec<u8>) -> Result<String, ProtocolError> {
+    match String::from_utf8(field) {
+        Ok(room) if valid_room(&room) => Ok(room),
+        _ => Err(ProtocolError::Malformed(tag)),
+    }
+}
+
+/// Reads a username out of a field, rejecting the frame if it isn't a valid one.
+fn user_field(tag: [u8; 3], field: Vec<u8>) -> Result<String, ProtocolError> {
+    match String::from_utf8(field) {
+        Ok(user) if valid_user(&user) => Ok(user),
+        _ => Err(ProtocolError::Malformed(tag)),
+    }
+}
+
+/// Reads a sequence number, written as 8 bytes big-endian, out of a field.
+fn seq_field(tag: [u8; 3], field: Vec<u8>) -> Result<u64, ProtocolError> {
+    let seq = field
+        .try_into()
+        .map_err(|_| ProtocolError::Malformed(tag))?;
+    Ok(u64::from_be_bytes(seq))
+}
+
+/// The data authenticated along with the message in a `DMS` frame: the names of the sender and the recipient.
+fn direct_aad(from: &str, to: &str) -> Vec<u8> {
+    join_fields(&[from.as_bytes(), to.as_bytes()])
+}
+
+/// What the sender of a `DMS` frame signs: the names of the sender and the recipient, the wrapped key and the sealed message.
+fn direct_signed(from: &str, to: &str, wrapped: &[u8], sealed: &[u8]) -> Vec<u8> {
+    join_fields(&[from.as_bytes(), to.as_bytes(), wrapped, sealed])
+}
+
+/// The data authenticated along with the message in an `ENC` frame: its tag, followed by the room.
+///
+/// The rest of the header isn't included, since the length of the body changes with the sequence number the server gives it.
+fn enc_aad(room: &str) -> Vec<u8> {
+    join_fields(&[b"ENC", room.as_bytes()])
+}
+
+fn header(tag: &[u8; 3], len: usize) -> [u8; HEADER_SIZE] {
+    let mut t = [0u8; HEADER_SIZE];
+    t[0..3].copy_from_slice(tag);
+    t[3..].copy_from_slice(&(len as u32).to_be_bytes());
+    t
+}
+
+/// Writes each field as a 4 byte big-endian length followed by the field.
+fn join_fields(fields: &[&[u8]]) -> Vec<u8> {
+    let mut t = Vec::with_capacity(fields.iter().map(|f| 4 + f.len()).sum());
+    for f in fields {
+        t.extend_from_slice(&(f.len() as u32).to_be_bytes());
+        t.extend_from_slice(f);
+    }
+    t
+}
+
+/// Reads exactly `N` fields written by `join_fields` out of the body of a frame.
+fn split_fields<const N: usize>(
+    tag: [u8; 3],
+    mut body: &[u8],
+) -> Result<[Vec<u8>; N], ProtocolError> {
+    let mut fields: [Vec<u8>; N] = std::array::from_fn(|_| Vec::new());
+    for field in fields.iter_mut() {
+        let (f, rest) = split_field(tag, body)?;
+        *field = f.to_vec();
+        body = rest;
+    }
+    if !body.is_empty() {
+        return Err(ProtocolError::Malformed(tag));
+    }
+    Ok(fields)
+}
+
+/// Reads every field written by `join_fields` out of the body of a frame, however many there are.
+fn split_all(tag: [u8; 3], mut body: &[u8]) -> Result<Vec<Vec<u8>>, ProtocolError> {
+    let mut fields = Vec::new();
+    while !body.is_empty() {
+        let (field, rest) = split_field(tag, body)?;
+        fields.push(field.to_vec());
+        body = rest;
+    }
+    Ok(fields)
+}
+
+/// Splits the first field off the front of `body`, returning it and the rest of the body.
+fn split_field(tag: [u8; 3], body: &[u8]) -> Result<(&[u8], &[u8]), ProtocolError> {
+    if body.len() < 4 {
+        return Err(ProtocolError::Malformed(tag));
+    }
+    let len = u32::from_be_bytes([body[0], body[1], body[2], body[3]]) as usize;
+    if body.len() - 4 < len {
+        return Err(ProtocolError::Malformed(tag));
+    }
+    Ok((&body[4..4 + len], &body[4 + len..]))
+}
+
+/// An error from a peer breaking the wire protocol.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// A frame had a tag we don't know.
+    UnknownFrame([u8; 3]),
+    /// A frame was longer than its limit in `FrameLimits`.
+    FrameTooLarge {
+        tag: [u8; 3],
+        len: usize,
+        max: usize,
+    },
+    /// A valid frame arrived at a point where it makes no sense, e.g. a `PUB` sent by the server.
+    UnexpectedFrame([u8; 3]),
+    /// The body of a frame could not be split into the fields it should have.
+    Malformed([u8; 3]),
+    /// The stream ended halfway through a frame.
+    Truncated,
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownFrame(tag) => {
+                write!(f, "unknown frame '{}'", String::from_utf8_lossy(tag))
+            }
+            Self::FrameTooLarge { tag, len, max } => write!(
+                f,
+                "'{}' frame of {len} bytes is over the limit of {max} bytes",
+                String::from_utf8_lossy(tag)
+            ),
+            Self::UnexpectedFrame(tag) => {
+                write!(f, "unexpected '{}' frame", String::from_utf8_lossy(tag))
+            }
+            Self::Malformed(tag) => {
+                write!(f, "malformed '{}' frame", String::from_utf8_lossy(tag))
+            }
+            Self::Truncated => write!(f, "the stream ended in the middle of a frame"),
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+/// ### Frame Limits
+///
+/// The largest body, in bytes, that will be accepted for each type of frame.
+///
+/// The length of a frame comes straight from the peer, so these stop a hostile or buggy peer from making us allocate as much as it likes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameLimits {
+    pub max_pub: usize,
+    pub max_idn: usize,
+    pub max_prv: usize,
+    pub max_enc: usize,
+    pub max_jon: usize,
+    pub max_lev: usize,
+    pub max_nck: usize,
+    pub max_krq: usize,
+    pub max_key: usize,
+    pub max_dms: usize,
+    pub max_err: usize,
+    pub max_who: usize,
+    pub max_usr: usize,
+    pub max_prs: usize,
+    pub max_typ: usize,
+    pub max_his: usize,
+}
+
+impl FrameLimits {
+    /// The largest body allowed for the frame with the given tag, or `None` if the tag is unknown.
+    pub fn max_len(&self, tag: &[u8; 3]) -> Option<usize> {
+        match tag {
+            b"PUB" => Some(self.max_pub),
+            b"IDN" => Some(self.max_idn),
+            b"PRV" => Some(self.max_prv),
+            b"ENC" => Some(self.max_enc),
+            b"JON" => Some(self.max_jon),
+            b"LEV" => Some(self.max_lev),
+            b"NCK" => Some(self.max_nck),
+            b"KRQ" => Some(self.max_krq),
+            b"KEY" => Some(self.max_key),
+            b"DMS" => Some(self.max_dms),
+            b"ERR" => Some(self.max_err),
+            b"WHO" => Some(self.max_who),
+            b"USR" => Some(self.max_usr),
+            b"PRS" => Some(self.max_prs),
+            b"TYP" => Some(self.max_typ),
+            b"HIS" => Some(self.max_his),
+            _ => None,
+        }
+    }
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            max_pub: 4 * 1024, // Comfortably fits a DER-encoded 8192 bit RSA public key.
+            max_idn: 8 * 1024, // The same, plus a signature made with it.
+            max_prv: 1024,     // A key wrapped with an 8192 bit RSA key is 1024 bytes.
+            max_enc: 64 * 1024,
+            max_jon: 4 * MAX_ROOM_LEN, // A room name, at up to 4 bytes per character.
+            max_lev: 4 * MAX_ROOM_LEN,
+            max_nck: 4 * MAX_USER_LEN, // A username, at up to 4 bytes per character.
+            max_krq: 4 * MAX_USER_LEN,
+            max_key: 8 * 1024,  // A username and a public key.
+            max_dms: 72 * 1024, // Like ENC, plus a username, a wrapped key, and the sender's public key and signature.
+            max_err: 1024,
+            max_who: 0,
+            max_usr: 256 * 1024,            // Room for a few thousand usernames.
+            max_prs: 16 + 8 * MAX_USER_LEN, // What happened, and up to two usernames.
+            max_typ: 16 + 4 * MAX_ROOM_LEN + 4 * MAX_USER_LEN, // A room or a username, and a username.
+            max_his: 28 + 4 * MAX_ROOM_LEN,                    // A room and two sequence numbers.
+        }
+    }
+}
+
+/// ### Frame Codec
+///
+/// Encodes and decodes `Frame`s. Wrap any `AsyncRead`/`AsyncWrite` with `tokio_util::codec::Framed` (or `FramedRead`/`FramedWrite`) to use it.
+///
+/// Frames with an unknown tag, or with a body larger than its `FrameLimits`, are rejected with a `ProtocolError`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FrameCodec {
+    limits: FrameLimits,
+}
+
+impl FrameCodec {
+    /// Constructs a new FrameCodec that enforces the given limits.
+    pub fn new(limits: FrameLimits) -> Self {
+        Self { limits }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = ConnectionError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Self::Error> {
+        // Wait until the whole header is here.
+        if src.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        let mut tag = [0u8; 3];
+        tag.copy_from_slice(&src[0..3]);
+        let len = u32::from_be_bytes([src[3], src[4], src[5], src[6]]) as usize;
+
+        // Check the header before waiting on (and allocating for) the body.
+        let Some(max) = self.limits.max_len(&tag) else {
+            return Err(ProtocolError::UnknownFrame(tag).into());
+        };
+        if len > max {
+            return Err(ProtocolError::FrameTooLarge { tag, len, max }.into());
+        }
+
+        // Then wait until the whole body is here.
+        if src.len() < HEADER_SIZE + len {
+            src.reserve(HEADER_SIZE + len - src.len());
+            return Ok(None);
+        }
+        src.advance(HEADER_SIZE);
+        let body = src.split_to(len).to_vec();
+
+        Ok(Some(Frame::from_parts(tag, body)?))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Self::Error> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => Err(ProtocolError::Truncated.into()),
+        }
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = ConnectionError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let body = frame.body();
+
+        // The other end would only hang up on a frame over its limit, so it is never sent.
+        let tag = *frame.tag();
+        if let Some(max) = self.limits.max_len(&tag).filter(|max| body.len() > *max) {
+            return Err(ProtocolError::FrameTooLarge {
+                tag,
+                len: body.len(),
+                max,
+            }
+            .into());
+        }
+        dst.reserve(HEADER_SIZE + body.len());
+        dst.put_slice(&header(frame.tag(), body.len()));
+        dst.put_slice(&body);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{valid_room, valid_user, Frame, FrameCodec, FrameLimits, Presence, ProtocolError},
+        crate::{
+            crypto::{CryptoError, GroupKey, Identity},
+            message::Target,
+            prelude::ConnectionError,
+        },
+        bytes::BytesMut,
+        futures::{SinkExt, StreamExt},
+        openssl::rsa::Rsa,
+        tokio::io::AsyncWriteExt,
+        tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite},
+    };
+
+    #[test]
+    fn round_trip_test() {
+        let frames = [
+            Frame::Pub(b"public key".to_vec()),
+            Frame::Idn {
+                key: b"identity".to_vec(),
+                signature: vec![],
+                epoch: 1_700_000_000,
+            },
+            Frame::Prv(b"wrapped key".to_vec()),
+            Frame::Enc {
+                room: "general".to_owned(),
+                seq: 42,
+                sealed: vec![],
+            },
+            Frame::Join("général".to_owned()),
+            Frame::Leave("general".to_owned()),
+            Frame::Nick("alice".to_owned()),
+            Frame::KeyRequest("bob".to_owned()),
+            Frame::Key {
+                user: "bob".to_owned(),
+                key: vec![],
+            },
+            Frame::Direct {
+                peer: "bob".to_owned(),
+                seq: u64::MAX,
+                sender_key: b"public key".to_vec(),
+                wrapped: b"wrapped key".to_vec(),
+                sealed: b"sealed".to_vec(),
+                signature: b"signature".to_vec(),
+            },
+            Frame::Error("no".to_owned()),
+            Frame::Who,
+            Frame::Users(vec![]),
+            Frame::Users(vec!["alice".to_owned(), "bob".to_owned()]),
+            Frame::Presence(Presence::Joined("alice".to_owned())),
+            Frame::Presence(Presence::Left("alice".to_owned())),
+            Frame::Presence(Presence::Renamed {
+                from: "alice".to_owned(),
+                to: "alicia".to_owned(),
+            }),
+            Frame::Typing {
+                to: Target::Room("general".to_owned()),
+                from: "alice".to_owned(),
+            },
+            Frame::Typing {
+                to: Target::User("bob".to_owned()),
+                from: "alice".to_owned(),
+            },
+            Frame::History {
+                room: "general".to_owned(),
+                after: 7,
+                before: 0,
+            },
+        ];
+
+        let mut buf = BytesMut::new();
+        for f in frames.iter().cloned() {
+            FrameCodec::default().encode(f, &mut buf).unwrap();
+        }
+        for f in frames {
+            assert_eq!(FrameCodec::default().decode(&mut buf).unwrap(), Some(f));
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn partial_test() {
+        let mut buf = BytesMut::new();
+        FrameCodec::default()
+            .encode(Frame::Pub(b"public key".to_vec()), &mut buf)
+            .unwrap();
+
+        // Feed the frame in one byte at a time. Nothing should come out until the last byte.
+        let mut partial = BytesMut::new();
+        let len = buf.len();
+        for (idx, b) in buf.iter().enumerate() {
+            partial.extend_from_slice(&[*b]);
+            let res = FrameCodec::default().decode(&mut partial).unwrap();
+            assert_eq!(res.is_some(), idx == len - 1);
+        }
+    }
+
+    #[test]
+    fn seal_test() {
+        let key = GroupKey::generate().unwrap();
+        let frame = Frame::seal(&key, "general", b"Hello there!").unwrap();
+        let Frame::Enc { room, seq, sealed } = &frame else {
+            panic!("expected an ENC frame");
+        };
+        assert_eq!(*seq, 0);
+        assert_eq!(Frame::open(&key, room, sealed).unwrap(), b"Hello there!");
+
+        // Moving the message to another room breaks the seal.
+        assert!(Frame::open(&key, "random", sealed).is_err());
+    }
+
+    #[test]
+    fn direct_test() {
+        let alice = Identity::generate().unwrap();
+        let bob = Rsa::generate(2048).unwrap();
+        let bob_pub = Rsa::public_key_from_der(&bob.public_key_to_der().unwrap()).unwrap();
+        let frame = Frame::seal_direct(&alice, &bob_pub, "alice", "bob", b"Psst").unwrap();
+        let Frame::Direct {
+            peer,
+            sender_key,
+            wrapped,
+            sealed,
+            signature,
+            ..
+        } = &frame
+        else {
+            panic!("expected a DMS frame");
+        };
+        assert_eq!(peer, "bob");
+        assert_eq!(*sender_key, alice.public_key().unwrap());
+        let open =
+            |rsa, from, key| Frame::open_direct(rsa, from, "bob", key, wrapped, sealed, signature);
+        assert_eq!(open(&bob, "alice", sender_key).unwrap(), b"Psst");
+
+        // Claiming it came from someone else breaks the seal, and no one else can open it.
+        assert!(open(&bob, "mallory", sender_key).is_err());
+        let mallory = Identity::generate().unwrap();
+        let mallory_rsa = mallory.rsa().unwrap();
+        assert!(open(&mallory_rsa, "alice", sender_key).is_err());
+
+        // Nor can someone else's key stand in for Alice's.
+        assert!(matches!(
+            open(&bob, "alice", &mallory.public_key().unwrap()),
+            Err(CryptoError::Authentication)
+        ));
+    }
+
+    #[test]
+    fn room_test() {
+        assert!(valid_room("general"));
+        assert!(valid_room("café"));
+        assert!(valid_room(&"a".repeat(32)));
+        assert!(!valid_room(""));
+        assert!(!valid_room(&"a".repeat(33)));
+        assert!(!valid_room("two words"));
+        assert!(!valid_room("bell\x07"));
+        assert!(valid_user("alice"));
+        assert!(!valid_user("alice smith"));
+
+        // Invalid room names are rejected when decoding.
+        let mut buf = BytesMut::new();
+        FrameCodec::default()
+            .encode(Frame::Join("two words".to_owned()), &mut buf)
+            .unwrap();
+        assert!(matches!(
+            FrameCodec::default().decode(&mut buf),
+            Err(ConnectionError::Protocol(ProtocolError::Malformed(_)))
+        ));
+    }
+
+    /// Sends frames through an in-memory stream.
+    #[tokio::test]
+    async fn stream_test() {
+        let (a, b) = tokio::io::duplex(64);
+        let mut writer = FramedWrite::new(a, FrameCodec::default());
+        let mut reader = FramedRead::new(b, FrameCodec::default());
+
+        let frame = Frame::Enc {
+            room: "general".to_owned(),
+            seq: 1,
+            sealed: vec![7u8; 1000], // Bigger than the duplex buffer.
+        };
+        let sent = frame.clone();
+        tokio::spawn(async move {
+            writer.send(sent).await.unwrap();
+        });
+        assert_eq!(reader.next().await.unwrap().unwrap(), frame);
+        assert!(reader.next().await.is_none());
+    }
+
+    /// Writes raw bytes into an in-memory stream and returns what the codec makes of them.
+    async fn read_raw(bytes: &[u8], limits: FrameLimits) -> Vec<Result<Frame, ConnectionError>> {
+        let (mut a, b) = tokio::io::duplex(64 * 1024);
+        a.write_all(bytes).await.unwrap();
+        drop(a);
+        FramedRead::new(b, FrameCodec::new(limits)).collect().await
+    }
+
+    #[tokio::test]
+    async fn oversized_test() {
+        // A header claiming a 4 GiB body should be rejected straight away, without waiting for the body.
+        let res = read_raw(b"ENC\xff\xff\xff\xff", FrameLimits::default()).await;
+        assert_eq!(res.len(), 1);
+        assert!(
+            matches!(
+                res[0],
+                Err(ConnectionError::Protocol(ProtocolError::FrameTooLarge {
+                    tag: [b'E', b'N', b'C'],
+                    len: 0xffff_ffff,
+                    ..
+                }))
+            ),
+            "{res:?}"
+        );
+
+        // Limits are per frame type.
+        let limits = FrameLimits {
+            max_pub: 4,
+            ..FrameLimits::default()
+        };
+        let res = read_raw(b"PUB\0\0\0\x05hello", limits).await;
+        assert!(res[0].is_err());
+        let res = read_raw(b"PRV\0\0\0\x05hello", limits).await;
+        assert_eq!(res[0].as_ref().unwrap(), &Frame::Prv(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn oversized_encode_test() {
+        // Nothing over the limit is written, so the codec can carry on with the next frame.
+        let mut codec = FrameCodec::new(FrameLimits {
+            max_pub: 4,
+            ..FrameLimits::default()
+        });
+        let mut buf = BytesMut::new();
+        let res = codec.encode(Frame::Pub(b"hello".to_vec()), &mut buf);
+        assert!(
+            matches!(
+                res,
+                Err(ConnectionError::Protocol(ProtocolError::FrameTooLarge {
+                    tag: [b'P', b'U', b'B'],
+                    len: 5,
+                    max: 4,
+                }))
+            ),
+            "{res:?}"
+        );
+        assert!(buf.is_empty());
+
+        codec.encode(Frame::Pub(b"hi".to_vec()), &mut buf).unwrap();
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Frame::Pub(b"hi".to_vec()))
+        );
+
+        // A message too long for the default limits.
+        let frame = Frame::Enc {
+            room: "general".to_owned(),
+            seq: 0,
+            sealed: vec![0; 65 * 1024],
+        };
+        assert!(FrameCodec::default().encode(frame, &mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_test() {
+        let res = read_raw(b"XYZ\0\0\0\0", FrameLimits::default()).await;
+        assert_eq!(res.len(), 1);
+        assert!(
+            matches!(
+                res[0],
+                Err(ConnectionError::Protocol(ProtocolError::UnknownFrame(_)))
+            ),
+            "{res:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_test() {
+        // The stream ends halfway through the body.
+        let res = read_raw(b"ENC\0\0\0\x05hel", FrameLimits::default()).await;
+        assert_eq!(res.len(), 1);
+        assert!(matches!(
+            res[0],
+            Err(ConnectionError::Protocol(ProtocolError::Truncated))
+        ));
+
+        // Or halfway through the header.
+        let res = read_raw(b"EN", FrameLimits::default()).await;
+        assert_eq!(res.len(), 1);
+        assert!(matches!(
+            res[0],
+            Err(ConnectionError::Protocol(ProtocolError::Truncated))
+        ));
+    }
+
+    #[tokio::test]
+    async fn malformed_test() {
+        // The first field claims to be longer than the whole body.
+        let res = read_raw(b"IDN\0\0\0\x05\0\0\0\x09k", FrameLimits::default()).await;
+        assert!(matches!(
+            res[0],
+            Err(ConnectionError::Protocol(ProtocolError::Malformed(_)))
+        ));
+
+        // Trailing bytes after the last field.
+        let res = read_raw(b"IDN\0\0\0\x0a\0\0\0\x01k\0\0\0\0!", FrameLimits::default()).await;
+        assert!(matches!(
+            res[0],
+            Err(ConnectionError::Protocol(ProtocolError::Malformed(_)))
+        ));
+
+        // A sequence number that isn't 8 bytes long.
+        let res = read_raw(
+            b"ENC\0\0\0\x14\0\0\0\x01g\0\0\0\x07\0\0\0\0\0\0\x01\0\0\0\0",
+            FrameLimits::default(),
+        )
+        .await;
+        assert!(matches!(
+            res[0],
+            Err(ConnectionError::Protocol(ProtocolError::Malformed(_)))
+        ));
+    }
+}
@@ -0,0 +1,504 @@
+use {
+    crate::{
+        message_list::{MessageList, MessageView},
+        theme::Theme,
+    },
+    chat_app::{
+        message::{Message, Target},
+        protocol::{valid_room, valid_user},
+    },
+    std::{collections::HashSet, fmt::Display},
+};
+
+/// ### Entry
+///
+/// Something shown in a room: a message, or a line from the client itself.
+#[derive(Debug, Clone)]
+pub enum Entry {
+    Message(Message),
+    Notice { kind: NoticeKind, text: String },
+}
+
+/// ### Notice Kind
+///
+/// What a line from the client is, which decides the header it is shown under and its color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoticeKind {
+    /// Something happened, like connecting or someone coming online.
+    System,
+    /// Something went wrong.
+    Error,
+    /// The answer to `/help`.
+    Help,
+}
+
+impl Display for NoticeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::System => write!(f, "system"),
+            Self::Error => write!(f, "error"),
+            Self::Help => write!(f, "help"),
+        }
+    }
+}
+
+/// ### Room
+///
+/// A room the user has joined, or a direct conversation with another user, with the messages shown in it.
+pub struct Room {
+    pub target: Target,
+    /// Where the messages of the room are scrolled to.
+    pub view: MessageView,
+    /// How many messages arrived while the user was looking at another room.
+    pub unread: usize,
+    /// Everything shown in the room, with the messages in the order of their sequence numbers.
+    entries: Vec<Entry>,
+    /// How many of the entries at the top were reloaded from the history, or recieved before the server last restarted. The rest are ordered after them.
+    loaded: usize,
+    /// The ids of every message recieved in the room, so copies of one are only shown once.
+    ids: HashSet<String>,
+    /// The last `before` the server was asked for the history of the room with. It isn't asked again while the answer is on its way, or once it had nothing older.
+    requested: Option<u64>,
+}
+
+impl Room {
+    fn new(target: Target) -> Self {
+        Self {
+            target,
+            view: MessageView::default(),
+            unread: 0,
+            entries: Vec::new(),
+            loaded: 0,
+            ids: HashSet::new(),
+            requested: None,
+        }
+    }
+
+    /// Clears the messages shown in the room. They won't come back if they are recieved again.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.loaded = 0;
+        self.requested = None;
+        self.view = MessageView::default();
+    }
+
+    /// The widget showing the messages of the room to the user `me` in the colors of `theme`, with a border around it, and the view it is scrolled with.
+    pub fn widget<'a>(
+        &'a mut self,
+        time_format: &'a str,
+        me: &'a str,
+        theme: &'a Theme,
+    ) -> (MessageList<'a>, &'a mut MessageView) {
+        let block = theme.block().title(format!("Messages - {}", self.target));
+        let list = MessageList::new(&self.entries, time_format, me, theme).block(block);
+        (list, &mut self.view)
+    }
+
+    /// Adds a line from the client itself to the end of the room.
+    pub fn notice(&mut self, kind: NoticeKind, text: &str) {
+        self.entries.push(Entry::Notice {
+            kind,
+            text: text.to_owned(),
+        });
+    }
+
+    /// The lowest sequence number of the messages recieved since the room was opened, to ask the server for the ones before it.
+    pub fn oldest_seq(&self) -> Option<u64> {
+        self.entries[self.loaded..]
+            .iter()
+            .filter_map(|e| match e {
+                Entry::Message(m) => m.seq(),
+                Entry::Notice { .. } => None,
+            })
+            .min()
+    }
+
+    /// The sequence number to ask the server for the messages before, or None if they were already asked for.
+    pub fn older(&mut self) -> Option<u64> {
+        let before = self.oldest_seq().unwrap_or(0);
+        (self.requested.replace(before) != Some(before)).then_some(before)
+    }
+
+    /// Puts messages reloaded from the history at the top of the room, in the order they were kept.
+    ///
+    /// Sequence numbers start again when the server restarts, so messages recieved from now on always go after them.
+    pub fn load(&mut self, msgs: Vec<Message>) {
+        let msgs: Vec<_> = msgs
+            .into_iter()
+            .filter(|m| self.ids.insert(m.id().to_owned()))
+            .map(Entry::Message)
+            .collect();
+        self.loaded += msgs.len();
+        self.entries.splice(0..0, msgs);
+    }
+
+    /// Forgets the history asked for on the last connection, as the answer may have been lost with it.
+    ///
+    /// If the server restarted, its sequence numbers started again, so everything in the room is kept above the messages recieved from now on.
+    fn connected(&mut self, restarted: bool) {
+        self.requested = None;
+        if restarted {
+            self.loaded = self.entries.len();
+        }
+    }
+
+    /// Puts a message in its place: before the first message with a higher sequence number, or at the end if there isn't one.
+    /// Messages without one, which haven't been through the server, always go at the end.
+    ///
+    /// Returns where it was put, or None if it was already recieved.
+    fn insert(&mut self, msg: Message) -> Option<usize> {
+        if !self.ids.insert(msg.id().to_owned()) {
+            return None;
+        }
+        let later = |e: &Entry| matches!((e, msg.seq()), (Entry::Message(m), Some(seq)) if m.seq().is_some_and(|s| s > seq));
+        let idx = self.entries[self.loaded..]
+            .iter()
+            .position(later)
+            .map_or(self.entries.len(), |idx| self.loaded + idx);
+        self.entries.insert(idx, Entry::Message(msg));
+        if idx + 1 == self.entries.len() {
+            self.view.arrived();
+        }
+        Some(idx)
+    }
+}
+
+/// ### Rooms
+///
+/// The rooms the user has joined and the direct conversations they have open, in the order they were opened, and the one they are looking at.
+///
+/// There is always at least one of them.
+pub struct Rooms {
+    list: Vec<Room>,
+    current: usize,
+}
+
+impl Rooms {
+    /// Constructs a new Rooms with only the room `first` joined.
+    pub fn new(first: &str) -> Self {
+        Self {
+            list: vec![Room::new(Target::Room(first.to_owned()))],
+            current: 0,
+        }
+    }
+
+    /// Every room and direct conversation, in the order they were opened.
+    pub fn iter(&self) -> impl Iterator<Item = &Room> {
+        self.list.iter()
+    }
+
+    /// The index of the room the user is looking at.
+    pub fn current_index(&self) -> usize {
+        self.current
+    }
+
+    /// The room the user is looking at.
+    pub fn current(&self) -> &Room {
+        &self.list[self.current]
+    }
+
+    /// The room the user is looking at.
+    pub fn current_mut(&mut self) -> &mut Room {
+        &mut self.list[self.current]
+    }
+
+    /// Joins a room, or opens a direct conversation, and switches to it.
+    ///
+    /// Returns false if it was already open, in which case it is only switched to.
+    pub fn join(&mut self, target: &Target) -> bool {
+        if let Some(idx) = self.position(target) {
+            self.select(idx);
+            return false;
+        }
+        self.list.push(Room::new(target.clone()));
+        self.select(self.list.len() - 1);
+        true
+    }
+
+    /// Leaves the room, or closes the direct conversation, the user is looking at, and switches to the one before it.
+    ///
+    /// Returns what was left, or None if it is the only one left.
+    pub fn leave_current(&mut self) -> Option<Target> {
+        if self.list.len() == 1 {
+            return None;
+        }
+        let room = self.list.remove(self.current);
+        self.select(self.current.saturating_sub(1));
+        Some(room.target)
+    }
+
+    /// Switches to the room at `idx`, if there is one, marking everything in it as read.
+    pub fn select(&mut self, idx: usize) {
+        if idx < self.list.len() {
+            self.current = idx;
+            self.list[idx].unread = 0;
+        }
+    }
+
+    /// Switches to the next room, going back around to the first one after the last.
+    pub fn next(&mut self) {
+        self.select((self.current + 1) % self.list.len());
+    }
+
+    /// Switches to the previous room, going back around to the last one before the first.
+    pub fn prev(&mut self) {
+        self.select((self.current + self.list.len() - 1) % self.list.len());
+    }
+
+    /// Puts a message recieved in `target` in its room, counting it as unread if the user is looking at something else.
+    ///
+    /// A direct conversation is opened if it isn't already, without switching to it.
+    ///
+    /// Returns the room and where in it the message was put, or None if the room isn't joined or the message was already recieved.
+    pub fn recieve(&mut self, target: &Target, msg: Message) -> Option<(&mut Room, usize)> {
+        self.open(target);
+        let idx = self.position(target)?;
+        let current = idx == self.current;
+        let room = &mut self.list[idx];
+        let idx = room.insert(msg)?;
+        if !current {
+            room.unread += 1;
+        }
+        Some((room, idx))
+    }
+
+    /// Gets every room ready for a new connection to the server, which may have `restarted` since the last one. See `Room::connected`.
+    pub fn connected(&mut self, restarted: bool) {
+        for room in &mut self.list {
+            room.connected(restarted);
+        }
+    }
+
+    /// Opens a direct conversation without switching to it. Rooms have to be joined instead.
+    ///
+    /// Returns the new conversation, or None if it was open already.
+    pub fn open(&mut self, target: &Target) -> Option<&mut Room> {
+        if matches!(target, Target::Room(_)) || self.position(target).is_some() {
+            return None;
+        }
+        self.list.push(Room::new(target.clone()));
+        self.list.last_mut()
+    }
+
+    fn position(&self, target: &Target) -> Option<usize> {
+        self.list.iter().position(|r| r.target == *target)
+    }
+}
+
+/// Parses a room name typed by the user, with or without a leading `#`.
+///
+/// Returns None if it isn't a valid room name.
+pub fn parse_room(s: &str) -> Option<String> {
+    let name = s.trim();
+    let name = name.strip_prefix('#').unwrap_or(name);
+    valid_room(name).then(|| name.to_owned())
+}
+
+/// Parses a username typed by the user, with or without a leading `@`.
+///
+/// Returns None if it isn't a valid username.
+pub fn parse_user(s: &str) -> Option<String> {
+    let name = s.trim();
+    let name = name.strip_prefix('@').unwrap_or(name);
+    valid_user(name).then(|| name.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{parse_room, parse_user, Entry, NoticeKind, Rooms},
+        chat_app::message::{Message, Target},
+    };
+
+    fn names(rooms: &Rooms) -> Vec<String> {
+        rooms.iter().map(|r| r.target.to_string()).collect()
+    }
+
+    fn room(name: &str) -> Target {
+        Target::Room(name.to_owned())
+    }
+
+    /// A message to `room`, numbered `seq` by the server if it is given.
+    fn msg(room: &str, seq: Option<u64>) -> Message {
+        let mut m = Message::new("alice", room, "Hi");
+        if let Some(seq) = seq {
+            m.set_seq(seq);
+        }
+        m
+    }
+
+    #[test]
+    fn rooms_test() {
+        let mut rooms = Rooms::new("general");
+        assert!(rooms.join(&room("random")));
+        assert!(rooms.join(&room("rust")));
+        assert!(!rooms.join(&room("random"))); // Already joined, only switches to it.
+        assert_eq!(names(&rooms), ["#general", "#random", "#rust"]);
+        assert_eq!(rooms.current().target, room("random"));
+
+        // Messages to other rooms are unread until the room is switched to.
+        rooms
+            .recieve(&room("general"), msg("general", None))
+            .unwrap();
+        rooms
+            .recieve(&room("general"), msg("general", None))
+            .unwrap();
+        rooms.recieve(&room("random"), msg("random", None)).unwrap();
+        assert!(rooms
+            .recieve(&room("offtopic"), msg("offtopic", None))
+            .is_none());
+        assert_eq!(
+            rooms.iter().map(|r| r.unread).collect::<Vec<_>>(),
+            [2, 0, 0]
+        );
+        rooms.prev();
+        assert_eq!(rooms.current().target, room("general"));
+        assert_eq!(rooms.current().unread, 0);
+
+        // Switching wraps around.
+        rooms.prev();
+        assert_eq!(rooms.current().target, room("rust"));
+        rooms.next();
+        assert_eq!(rooms.current().target, room("general"));
+        rooms.select(7);
+        assert_eq!(rooms.current_index(), 0);
+
+        // Leaving switches to the room before, and the last room can't be left.
+        rooms.select(1);
+        assert_eq!(rooms.leave_current(), Some(room("random")));
+        assert_eq!(rooms.current().target, room("general"));
+        assert_eq!(rooms.leave_current(), Some(room("general")));
+        assert_eq!(rooms.leave_current(), None);
+        assert_eq!(names(&rooms), ["#rust"]);
+
+        // Direct messages open a conversation without switching to it.
+        let bob = Target::User("bob".to_owned());
+        rooms
+            .recieve(&bob, Message::direct("bob", "alice", "Hi"))
+            .unwrap();
+        assert_eq!(names(&rooms), ["#rust", "@bob"]);
+        assert_eq!(rooms.current().target, room("rust"));
+        assert_eq!(rooms.iter().last().unwrap().unread, 1);
+    }
+
+    #[test]
+    fn order_test() {
+        let mut rooms = Rooms::new("general");
+        let general = room("general");
+        rooms.current_mut().notice(NoticeKind::System, "Connected.");
+        assert_eq!(
+            rooms.recieve(&general, msg("general", Some(1))).unwrap().1,
+            1
+        );
+        assert_eq!(
+            rooms.recieve(&general, msg("general", Some(3))).unwrap().1,
+            2
+        );
+        assert_eq!(rooms.recieve(&general, msg("general", None)).unwrap().1, 3);
+
+        // A message that was overtaken goes before the ones with a higher number.
+        let late = msg("general", Some(2));
+        assert_eq!(rooms.recieve(&general, late.clone()).unwrap().1, 2);
+
+        // Copies are only shown once, and don't count as unread.
+        assert!(rooms.recieve(&general, late).is_none());
+        let seqs: Vec<_> = rooms
+            .current()
+            .entries
+            .iter()
+            .map(|e| match e {
+                Entry::Message(m) => m.seq(),
+                Entry::Notice { .. } => Some(0),
+            })
+            .collect();
+        assert_eq!(seqs, [Some(0), Some(1), Some(2), Some(3), None]);
+        assert_eq!(rooms.current().oldest_seq(), Some(1));
+        assert_eq!(rooms.current().unread, 0);
+
+        // Messages from the history stay at the top, whatever their number.
+        let old = msg("general", Some(9));
+        let mut rooms = Rooms::new("general");
+        rooms.current_mut().load(vec![old.clone()]);
+        assert_eq!(rooms.current().oldest_seq(), None);
+        assert_eq!(
+            rooms.recieve(&general, msg("general", Some(1))).unwrap().1,
+            1
+        );
+        assert!(rooms.recieve(&general, old).is_none());
+        assert_eq!(rooms.current().oldest_seq(), Some(1));
+        assert!(rooms.open(&general).is_none());
+        assert!(rooms.open(&Target::User("bob".to_owned())).is_some());
+
+        // Cleared messages stay cleared.
+        let m = msg("general", Some(4));
+        rooms.recieve(&general, m.clone()).unwrap();
+        rooms.current_mut().clear();
+        assert!(rooms.recieve(&general, m).is_none());
+        assert!(rooms.current().entries.is_empty());
+    }
+
+    #[test]
+    fn restart_test() {
+        let mut rooms = Rooms::new("general");
+        let general = room("general");
+        for seq in [7, 8] {
+            rooms.recieve(&general, msg("general", Some(seq))).unwrap();
+        }
+
+        // The server restarted while we were away, and numbers from 1 again.
+        rooms.connected(true);
+        assert_eq!(rooms.current().oldest_seq(), None);
+        assert_eq!(
+            rooms.recieve(&general, msg("general", Some(2))).unwrap().1,
+            2
+        );
+        assert_eq!(
+            rooms.recieve(&general, msg("general", Some(1))).unwrap().1,
+            2
+        );
+        let seqs: Vec<_> = rooms
+            .current()
+            .entries
+            .iter()
+            .map(|e| match e {
+                Entry::Message(m) => m.seq(),
+                Entry::Notice { .. } => None,
+            })
+            .collect();
+        assert_eq!(seqs, [Some(7), Some(8), Some(1), Some(2)]);
+        assert_eq!(rooms.current().oldest_seq(), Some(1));
+    }
+
+    #[test]
+    fn older_test() {
+        let mut rooms = Rooms::new("general");
+        let general = room("general");
+
+        // The newest messages first, and only once.
+        assert_eq!(rooms.current_mut().older(), Some(0));
+        assert_eq!(rooms.current_mut().older(), None);
+        for seq in [5, 6] {
+            rooms.recieve(&general, msg("general", Some(seq))).unwrap();
+        }
+
+        // Then the ones before them, until there are no more.
+        assert_eq!(rooms.current_mut().older(), Some(5));
+        rooms.recieve(&general, msg("general", Some(3))).unwrap();
+        assert_eq!(rooms.current_mut().older(), Some(3));
+        assert_eq!(rooms.current_mut().older(), None);
+
+        // The answer may have been lost with the connection, so it can be asked for again.
+        rooms.connected(false);
+        assert_eq!(rooms.current_mut().older(), Some(3));
+    }
+
+    #[test]
+    fn parse_room_test() {
+        assert_eq!(parse_room("general").as_deref(), Some("general"));
+        assert_eq!(parse_room(" #rust ").as_deref(), Some("rust"));
+        assert_eq!(parse_room("#"), None);
+        assert_eq!(parse_room("two words"), None);
+        assert_eq!(parse_user("@bob").as_deref(), Some("bob"));
+        assert_eq!(parse_user("bob smith"), None);
+    }
+}
@@ -0,0 +1,150 @@
+use {
+    chat_app::{message::Target, protocol::Presence},
+    std::{
+        collections::{BTreeSet, HashMap},
+        time::{Duration, Instant},
+    },
+};
+
+/// How long someone is shown as typing after the last time we were told they are.
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// ### Roster
+///
+/// Who is online, as last told by the server, in alphabetical order, and who is typing where.
+#[derive(Debug, Default)]
+pub struct Roster {
+    online: BTreeSet<String>,
+    /// When each user was last typing in each conversation.
+    typing: HashMap<(Target, String), Instant>,
+}
+
+impl Roster {
+    /// Replaces everyone with the list sent by the server.
+    pub fn set(&mut self, users: Vec<String>) {
+        self.online = users.into_iter().collect();
+    }
+
+    /// Forgets everyone, e.g. when the connection is lost.
+    pub fn clear(&mut self) {
+        self.online.clear();
+        self.typing.clear();
+    }
+
+    /// Applies a change sent by the server.
+    pub fn apply(&mut self, presence: &Presence) {
+        match presence {
+            Presence::Joined(user) => _ = self.online.insert(user.clone()),
+            Presence::Left(user) => {
+                self.online.remove(user);
+                self.typing.retain(|(_, u), _| u != user);
+            }
+            Presence::Renamed { from, to } => {
+                self.online.remove(from);
+                self.online.insert(to.clone());
+                self.typing.retain(|(_, u), _| u != from);
+            }
+        }
+    }
+
+    /// Everyone online, in alphabetical order.
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        self.online.iter()
+    }
+
+    /// How many users are online.
+    pub fn len(&self) -> usize {
+        self.online.len()
+    }
+
+    /// Notes that `user` is typing in `conversation`, forgetting anyone who stopped a while ago.
+    pub fn typing(&mut self, conversation: Target, user: String, now: Instant) {
+        self.typing
+            .retain(|_, t| now.duration_since(*t) < TYPING_TIMEOUT);
+        self.typing.insert((conversation, user), now);
+    }
+
+    /// Notes that `user` stopped typing in `conversation`, because their message arrived.
+    pub fn stopped(&mut self, conversation: &Target, user: &str) {
+        self.typing.remove(&(conversation.clone(), user.to_owned()));
+    }
+
+    /// The line shown under the messages of `conversation`, like `bob is typing…`, if anyone is.
+    pub fn typing_status(&self, conversation: &Target, now: Instant) -> Option<String> {
+        let mut users: Vec<_> = self
+            .typing
+            .iter()
+            .filter(|((c, _), t)| c == conversation && now.duration_since(**t) < TYPING_TIMEOUT)
+            .map(|((_, u), _)| u.as_str())
+            .collect();
+        users.sort();
+        match users.as_slice() {
+            [] => None,
+            [user] => Some(format!("{user} is typing…")),
+            [a, b] => Some(format!("{a} and {b} are typing…")),
+            _ => Some("Several people are typing…".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{Roster, TYPING_TIMEOUT},
+        chat_app::{message::Target, protocol::Presence},
+        std::time::{Duration, Instant},
+    };
+
+    #[test]
+    fn roster_test() {
+        let mut roster = Roster::default();
+        roster.set(vec!["carol".to_owned(), "alice".to_owned()]);
+        roster.apply(&Presence::Joined("bob".to_owned()));
+        roster.apply(&Presence::Joined("alice".to_owned())); // Already known.
+        assert_eq!(roster.iter().collect::<Vec<_>>(), ["alice", "bob", "carol"]);
+
+        roster.apply(&Presence::Renamed {
+            from: "bob".to_owned(),
+            to: "robert".to_owned(),
+        });
+        roster.apply(&Presence::Left("carol".to_owned()));
+        assert_eq!(roster.iter().collect::<Vec<_>>(), ["alice", "robert"]);
+        assert_eq!(roster.len(), 2);
+
+        roster.clear();
+        assert_eq!(roster.len(), 0);
+    }
+
+    #[test]
+    fn typing_test() {
+        let general = Target::Room("general".to_owned());
+        let bob = Target::User("bob".to_owned());
+        let now = Instant::now();
+
+        let mut roster = Roster::default();
+        assert_eq!(roster.typing_status(&general, now), None);
+        roster.typing(general.clone(), "carol".to_owned(), now);
+        roster.typing(general.clone(), "bob".to_owned(), now);
+        roster.typing(bob.clone(), "bob".to_owned(), now);
+        assert_eq!(
+            roster.typing_status(&general, now).unwrap(),
+            "bob and carol are typing…"
+        );
+        assert_eq!(roster.typing_status(&bob, now).unwrap(), "bob is typing…");
+
+        // Sending a message, or leaving, stops it.
+        roster.stopped(&general, "carol");
+        roster.apply(&Presence::Left("bob".to_owned()));
+        assert_eq!(roster.typing_status(&general, now), None);
+        assert_eq!(roster.typing_status(&bob, now), None);
+
+        // And so does time.
+        roster.typing(general.clone(), "alice".to_owned(), now);
+        let later = now + TYPING_TIMEOUT - Duration::from_millis(1);
+        assert_eq!(
+            roster.typing_status(&general, later).unwrap(),
+            "alice is typing…"
+        );
+        assert_eq!(roster.typing_status(&general, now + TYPING_TIMEOUT), None);
+    }
+}
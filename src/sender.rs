@@ -1,193 +1,663 @@
-use std::net::SocketAddr;
-
 use {
-    crate::{message::Message, prelude::ConnectionError},
-    openssl::{
-        pkey::Private,
-        rsa::{Padding, Rsa},
-        symm::{decrypt, encrypt, Cipher},
+    crate::{
+        address::Address,
+        event::{Command, ConnectionState, NetworkEvent},
+        known_hosts::{HostStatus, KnownHosts},
+        message::{Message, Target},
+        prelude::{error_chain, load_identity, ConnectionError},
+    },
+    chat_app::{
+        crypto::{fingerprint, verify, GroupKey, Identity},
+        protocol::{Frame, FrameCodec, ProtocolError},
     },
+    futures::{SinkExt, StreamExt},
+    openssl::rsa::Rsa,
     serde_json::json,
+    std::{
+        collections::{HashMap, VecDeque},
+        time::Duration,
+    },
     tokio::{
-        io::{AsyncReadExt, AsyncWriteExt},
         net::TcpStream,
+        sync::mpsc::{Receiver, Sender},
     },
+    tokio_util::codec::Framed,
 };
 
+type Connection = Framed<TcpStream, FrameCodec>;
+
 /// ### The main Sender loop.
 ///
 /// Loops ad infinitum. It will handle input, parsing of input, and recieving data to be sent to the reciever.
+///
+/// If the connection drops it reconnects with exponential backoff, redoing the handshake, and queues anything typed in the meantime.
+/// Returns an error only if something goes wrong that reconnecting can't fix, so the terminal can tell the user about it.
 pub async fn sender_loop(
-    mut rx: tokio::sync::mpsc::Receiver<String>,
-    stx: tokio::sync::mpsc::Sender<String>,
+    rx: Receiver<Command>,
+    stx: Sender<NetworkEvent>,
     user: String,
-    ip: String,
+    address: Address,
 ) -> Result<(), ConnectionError> {
-    const RSA_SIZE: u32 = 2048;
-    const SYMM_SIZE: usize = 32;
-    const DEFAULT_PORT: u16 = 42530;
-    let mut first = true;
-
-    // Make the connection to the server
-    // Make the socket from an ip. Default to 127.0.0.1:42530 upon an invalid ip
-    let mut sock = ip
-        .parse::<SocketAddr>()
-        .unwrap_or("127.0.0.1:42530".parse::<SocketAddr>().unwrap());
-    let mut stream = match TcpStream::connect(sock).await {
-        Ok(conn) => conn,
-        Err(_) => {
-            // If the connection failed, change the port to the default port and try again, returning if failing again.
-            sock.set_port(DEFAULT_PORT);
-            match TcpStream::connect(sock).await {
-                Ok(t) => t,
-                Err(_) => return Err(ConnectionError::new("connection refused")),
-            }
+    // The servers we have connected to before, and the fingerprints of their identity keys.
+    let known_hosts =
+        KnownHosts::load(&KnownHosts::default_path().unwrap_or("known_hosts".into()))?;
+    // The same for the users we have sent direct messages to, or gotten them from.
+    let known_users = KnownHosts::load(&KnownHosts::users_path().unwrap_or("known_users".into()))?;
+
+    // Our own long-term key, which other users remember us by.
+    let identity_path = dirs::config_dir()
+        .map(|d| d.join("chat_app").join("identity.pem"))
+        .unwrap_or("identity.pem".into());
+    let (identity, generated) = load_identity(&identity_path)?;
+    if generated {
+        let found = fingerprint(&identity.public_key()?);
+        stx.send(NetworkEvent::Notice(format!("Generated your identity key at {}. Its fingerprint is {found}, which others can check your direct messages against.", identity_path.display()))).await?;
+    }
+
+    Client::new(rx, stx, user, address, identity, known_hosts, known_users)
+        .run()
+        .await
+}
+
+/// ### Client
+///
+/// Everything the sender keeps between connections to the server.
+struct Client {
+    rx: Receiver<Command>,
+    stx: Sender<NetworkEvent>,
+    user: String,
+    address: Address,
+    /// Our long-term key. Its public half is what we send in the handshake, so direct messages are sealed to it, and we sign the ones we send with it.
+    identity: Identity,
+    known_hosts: KnownHosts,
+    /// The fingerprints of the keys of other users, as `user@host`.
+    known_users: KnownHosts,
+    /// The rooms the user has joined. They are joined again on every new connection.
+    rooms: Vec<String>,
+    /// The epoch of the server the last time we connected to it, to know when it restarted.
+    epoch: Option<u64>,
+    /// The highest sequence number seen in each room, so we only ask for what we missed when we join it again.
+    seen: HashMap<String, u64>,
+    /// Messages typed while we were not connected, to be sent once we are.
+    queue: VecDeque<Message>,
+    /// Direct messages waiting on the recipient's public key, by recipient.
+    pending: HashMap<String, Vec<Message>>,
+    backoff: Backoff,
+}
+
+impl Client {
+    fn new(
+        rx: Receiver<Command>,
+        stx: Sender<NetworkEvent>,
+        user: String,
+        address: Address,
+        identity: Identity,
+        known_hosts: KnownHosts,
+        known_users: KnownHosts,
+    ) -> Self {
+        Self {
+            rx,
+            stx,
+            user,
+            address,
+            identity,
+            known_hosts,
+            known_users,
+            rooms: Vec::new(),
+            epoch: None,
+            seen: HashMap::new(),
+            queue: VecDeque::new(),
+            pending: HashMap::new(),
+            backoff: Backoff::default(),
         }
-    };
+    }
 
-    let cl_rsa = Rsa::generate(RSA_SIZE).unwrap();
-    let ciph = Cipher::aes_256_cbc();
-    let mut sv_prv_key: Option<Rsa<Private>> = None;
+    /// Runs sessions with the server until the terminal goes away, reconnecting whenever one fails with a retryable error.
+    async fn run(mut self) -> Result<(), ConnectionError> {
+        loop {
+            let e = match self.session().await {
+                Ok(_) => return Ok(()),
+                Err(e) if e.is_retryable() => e,
+                Err(e) => return Err(e),
+            };
 
-    {
-        let pub_key = cl_rsa.public_key_to_der().unwrap();
-        let key_len = (pub_key.len() as u32).to_be_bytes();
-        let header = "PUB".as_bytes();
-        stream
-            .write_all(&[header, &key_len, &pub_key].concat())
-            .await
-            .unwrap();
+            let retry_in = self.backoff.next_delay();
+            self.event(NetworkEvent::State(ConnectionState::Disconnected {
+                reason: error_chain(&e),
+                retry_in,
+            }))
+            .await?;
+            if !self.wait(retry_in).await? {
+                return Ok(());
+            }
+        }
     }
 
-    // Main loop
-    loop {
-        let mut key_buf = [0u8; 3]; // Buffer to determine type of message.
-        let mut len_buf = [0u8; 4]; // Length buffer at the beginning of the packet.
-
-        // Check for either an incoming packet to be sent to the server, or a packet from the server.
-        tokio::select! {
-            result = stream.read_exact(&mut key_buf) => { // Check for message from server.
-                match result {
-                    Ok(0) => {
-                        stx.send("C".to_owned()).await.unwrap(); // Close on connection terminated
-                        break; // Break on close message.
-                    },
-                    Ok(_) => {
-                        stream.read_exact(&mut len_buf).await.unwrap(); // Get the length
-                        let key_len = u32::from_be_bytes(len_buf) as usize; // Parse to usize.
-                        let typ = String::from_utf8_lossy(&key_buf).to_string(); // Get the type of the packet.
-                        match typ.as_str() {
-                            "PRV" if first => {
-                                // Get the symmetrical key
-                                let mut key = vec![0u8; key_len];
-                                stream.read_exact(&mut key).await.unwrap();
-
-                                // Decrypt the symmetrical key
-                                let symm = {
-                                    let mut t = vec![0u8; RSA_SIZE as usize];
-                                    let l = cl_rsa.private_decrypt(&key, &mut t, Padding::PKCS1).unwrap();
-                                    t[0..l].to_owned()
-                                };
-
-                                // Get the DER-encoded private key length
-                                let mut der_len_buf = [0u8; 4];
-                                stream.read_exact(&mut der_len_buf).await.unwrap();
-                                let der_len = u32::from_be_bytes(der_len_buf);
-
-                                // Get the DER-encoded private key
-                                let mut der_enc = vec![0u8; der_len as usize];
-                                stream.read_exact(&mut der_enc).await.unwrap();
-
-                                // Generate the server's private key from the DER
-                                let der = decrypt(ciph, &symm, None, &der_enc).unwrap();
-                                sv_prv_key = Some(Rsa::private_key_from_der(&der).unwrap());
-
-                                first = false;
-                            },
-                            "ENC" => {
-                                if sv_prv_key.is_some() {
-                                    let mut key = vec![0u8; key_len];
-                                    stream.read_exact(&mut key).await.unwrap();
-
-                                    let mut msg_len = [0u8; 4];
-                                    stream.read_exact(&mut msg_len).await.unwrap();
-                                    let len = u32::from_be_bytes(msg_len) as usize;
-
-                                    let mut msg = vec![0u8; len];
-                                    stream.read_exact(&mut msg).await.unwrap();
-
-                                    let Some(dec_key) = sv_prv_key.as_mut() else {
-                                        break;
-                                    };
-
-                                    let k = {
-                                        let mut t = vec![0u8; RSA_SIZE as usize];
-                                        let len = dec_key.private_decrypt(&key, &mut t, Padding::PKCS1).unwrap();
-                                        t[0..len].to_owned()
-                                    };
-
-                                    let msg_str = decrypt(ciph, &k, None, &msg).unwrap();
-
-                                    let msg_str = String::from_utf8_lossy(&msg_str);
-                                    stx.send(msg_str.to_string()).await.unwrap();
-
-                                } else {
-                                    eprintln!("No");
-                                }
-                            },
-                            _ => return Ok(()),
+    /// Waits out the backoff, queueing any messages typed in the meantime.
+    ///
+    /// Returns false if the terminal went away while waiting.
+    async fn wait(&mut self, delay: Duration) -> Result<bool, ConnectionError> {
+        let sleep = tokio::time::sleep(delay);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => return Ok(true),
+                cmd = self.rx.recv() => match cmd {
+                    Some(Command::Send { to, payload, action }) => {
+                        if !payload.is_empty() {
+                            self.queue.push_back(self.message(to, &payload, action));
+                            self.notice("Not connected. Your message will be sent once the connection is back.").await?;
                         }
-                    },
-                    Err(e) if e.kind() == tokio::io::ErrorKind::UnexpectedEof => {
-                        stx.send("C".to_owned()).await.unwrap();
-                        break; // Break on close message.
-                    },
-                    Err(e) => {
-                        eprintln!("Error reading from server: {e:?}");
-                        break; // Break on error.
                     }
+                    Some(cmd) => self.command(cmd, None).await?, // The rooms are joined when we connect.
+                    None => return Ok(false),
                 }
-            },
-            Some(m) = rx.recv() => { // Check for message from the terminal.
-                if !m.is_empty() {
-                    if let Some(prv_rsa) = &sv_prv_key {
-                        let msg = Message::new(&user, &m);      // Create the message struct.
-                        let msg_bytes = {
-                            let t = json!(msg).to_string();
-                            t.as_bytes().to_vec()
-                        };
-                        let key = gen_rand_symm(SYMM_SIZE);
-
-                        let msg_enc = encrypt(ciph, &key, None, &msg_bytes).unwrap();
-                        let key_enc = {
-                            let mut t = vec![0u8; RSA_SIZE as usize];
-                            let len = prv_rsa.public_encrypt(&key, &mut t, Padding::PKCS1).unwrap();
-                            t[0..len].to_owned()
-                        };
-
-                        let msg_len = (msg_enc.len() as u32).to_be_bytes(); // Write the length header.
-                        let key_len = (key_enc.len() as u32).to_be_bytes();
-                        let key_header = "ENC".as_bytes();
-
-                        stream.write_all(&[key_header, &key_len, &key_enc, &msg_len, &msg_enc].concat()).await.unwrap(); // Write the header and the json to the connection.
-                        stream.flush().await.unwrap(); // Flush the connection buffer.
+            }
+        }
+    }
+
+    /// Connects to the server, does the handshake, then passes messages back and forth until something goes wrong.
+    ///
+    /// Returns Ok only when the terminal has gone away.
+    async fn session(&mut self) -> Result<(), ConnectionError> {
+        let ip = self.address.to_string(); // The name the server is remembered by in the known hosts.
+        self.event(NetworkEvent::State(ConnectionState::Connecting))
+            .await?;
+
+        // Direct messages still waiting on a key from the last connection have to ask for it again.
+        for (_, msgs) in self.pending.drain() {
+            self.queue.extend(msgs);
+        }
+
+        // Make the connection to the server
+        let stream = self.address.connect().await?;
+
+        let mut stream = Framed::new(stream, FrameCodec::default());
+        let cl_rsa = self.identity.rsa()?;
+        let mut group_key: Option<GroupKey> = None; // The group key, recieved from the server during the handshake.
+        let mut identity: Option<(Vec<u8>, Vec<u8>, u64)> = None; // The server's identity key, its signature over the handshake, and its epoch.
+
+        // Send our public key to start the handshake.
+        let pub_key = self.identity.public_key()?;
+        stream.send(Frame::Pub(pub_key.clone())).await?;
+
+        // Main loop
+        loop {
+            // Check for either an incoming packet to be sent to the server, or a packet from the server.
+            tokio::select! {
+                result = stream.next() => { // Check for message from server.
+                    match result {
+                        Some(Ok(Frame::Idn { key, signature, epoch })) if identity.is_none() => {
+                            // Check the server's identity key against the one we saw last time (trust on first use).
+                            // A new one is only remembered once its signature over the group key checks out.
+                            let found = fingerprint(&key);
+                            if let HostStatus::Mismatch { expected } = self.known_hosts.check(&ip, &found) {
+                                return Err(ConnectionError::HostKeyMismatch { host: ip, expected, found });
+                            }
+                            identity = Some((key, signature, epoch));
+                        },
+                        Some(Ok(Frame::Prv(key))) if group_key.is_none() => {
+                            // Make sure the group key really came from the server we just checked.
+                            let Some((id_key, signature, epoch)) = &identity else {
+                                return Err(ConnectionError::Handshake("the server did not identify itself".to_owned()));
+                            };
+                            if !verify(id_key, &[&pub_key[..], &key].concat(), signature).unwrap_or(false) {
+                                return Err(ConnectionError::Handshake("the server's signature does not match its identity key".to_owned()));
+                            }
+                            let found = fingerprint(id_key);
+                            if self.known_hosts.check(&ip, &found) == HostStatus::New {
+                                self.known_hosts.add(&ip, &found)?;
+                                self.notice(&format!("First connection to {ip}. The server's identity key fingerprint is {found}. Check it with the server's owner, it will be checked on every connection from now on.")).await?;
+                            }
+
+                            // Unwrap the group key with our private key
+                            let Some(k) = GroupKey::unwrap(&cl_rsa, &key)? else {
+                                return Err(ConnectionError::Handshake("the server sent an invalid group key".to_owned()));
+                            };
+
+                            // We are connected. Tell the server who we are, join our rooms and catch up on them, then send everything that was typed while we weren't.
+                            self.backoff.reset();
+                            let restarted = self.epoch.replace(*epoch).is_some_and(|e| e != *epoch);
+                            if restarted {
+                                self.seen.clear(); // The sequence numbers we saw mean nothing to it anymore.
+                            }
+                            self.event(NetworkEvent::State(ConnectionState::Connected { restarted })).await?;
+                            stream.send(Frame::Nick(self.user.clone())).await?;
+                            for room in &self.rooms {
+                                stream.send(Frame::Join(room.clone())).await?;
+                                stream.send(self.backfill(room)).await?;
+                            }
+                            while let Some(msg) = self.queue.front() {
+                                let msg = msg.clone();
+                                self.send_message(&mut stream, &k, msg).await?;
+                                self.queue.pop_front();
+                            }
+                            group_key = Some(k);
+                        },
+                        Some(Ok(Frame::Enc { room, seq, sealed })) => {
+                            let Some(key) = &group_key else {
+                                return Err(ConnectionError::Handshake("the server sent a message before the group key".to_owned()));
+                            };
+
+                            // The tag and the room are authenticated along with the message, but the sequence number comes from the server alone.
+                            // Anyone in the room can send one that doesn't open, and that shouldn't end the session either.
+                            let msg = match Frame::open(key, &room, &sealed) {
+                                Ok(msg) => msg,
+                                Err(source) => {
+                                    self.event(NetworkEvent::Error(ConnectionError::Undecryptable { from: Target::Room(room), source })).await?;
+                                    continue;
+                                },
+                            };
+
+                            // Someone sending something that isn't a message shouldn't end the session.
+                            match serde_json::from_slice::<Message>(&msg) {
+                                Ok(mut msg) if *msg.to() == Target::Room(room.clone()) => {
+                                    msg.set_seq(seq);
+                                    let seen = self.seen.entry(room).or_default();
+                                    *seen = (*seen).max(seq);
+                                    self.event(NetworkEvent::Message(msg)).await?
+                                },
+                                Ok(_) => self.event(NetworkEvent::Error(ProtocolError::Malformed(*b"ENC").into())).await?,
+                                Err(e) => self.event(NetworkEvent::Error(e.into())).await?,
+                            }
+                        },
+                        Some(Ok(Frame::Key { user, key })) => self.send_direct(&mut stream, &user, &key).await?,
+                        Some(Ok(Frame::Direct { peer, seq, sender_key, wrapped, sealed, signature })) => {
+                            // Only we can open it, and only if it is signed with the key we remember for who the server says it's from.
+                            if !self.check_user(&peer, &sender_key).await? {
+                                continue;
+                            }
+                            let msg = Frame::open_direct(&cl_rsa, &peer, &self.user, &sender_key, &wrapped, &sealed, &signature)
+                                .map_err(|source| ConnectionError::Undecryptable { from: Target::User(peer.clone()), source })
+                                .and_then(|msg| Ok(serde_json::from_slice::<Message>(&msg)?));
+                            match msg {
+                                Ok(mut msg) if msg.from() == peer && *msg.to() == Target::User(self.user.clone()) => {
+                                    // A new key is only remembered once it has signed something.
+                                    self.pin_user(&peer, &sender_key).await?;
+                                    msg.set_seq(seq);
+                                    self.event(NetworkEvent::Message(msg)).await?
+                                },
+                                Ok(_) => self.event(NetworkEvent::Error(ProtocolError::Malformed(*b"DMS").into())).await?,
+                                Err(e) => self.event(NetworkEvent::Error(e)).await?,
+                            }
+                        },
+                        Some(Ok(Frame::Nick(name))) => {
+                            self.user = name.clone();
+                            self.event(NetworkEvent::Nick(name)).await?;
+                        },
+                        Some(Ok(Frame::Users(users))) => self.event(NetworkEvent::Users(users)).await?,
+                        Some(Ok(Frame::Presence(presence))) => self.event(NetworkEvent::Presence(presence)).await?,
+                        Some(Ok(Frame::Typing { to, from })) => {
+                            // Someone typing to us is typing in our conversation with them.
+                            let conversation = match to {
+                                Target::User(_) => Target::User(from.clone()),
+                                room => room,
+                            };
+                            self.event(NetworkEvent::Typing { user: from, conversation }).await?;
+                        },
+                        Some(Ok(Frame::History { room, after, before })) => {
+                            // There is more to catch up on. It is only worth asking for if we are still in the room.
+                            if self.rooms.contains(&room) {
+                                stream.send(Frame::History { room, after, before }).await?;
+                            }
+                        },
+                        Some(Ok(Frame::Error(reason))) => self.event(NetworkEvent::Error(ConnectionError::Refused(reason))).await?,
+                        Some(Ok(frame)) => return Err(ProtocolError::UnexpectedFrame(*frame.tag()).into()),
+                        None => return Err(ConnectionError::Closed), // Connection terminated by the server.
+                        Some(Err(e)) => return Err(e), // Malformed or oversized frame, or a read error.
                     }
+                },
+                cmd = self.rx.recv() => match cmd { // Check for a command from the terminal.
+                    Some(Command::Send { to, payload, action }) => {
+                        if !payload.is_empty() {
+                            let msg = self.message(to, &payload, action); // Create the message struct.
+                            match &group_key {
+                                Some(key) => self.send_message(&mut stream, key, msg).await?,
+                                None => self.queue.push_back(msg), // Still in the handshake, send it once it's done.
+                            }
+                        }
+                    }
+                    Some(cmd) => {
+                        // If we're still in the handshake, the rooms will be joined once it's done.
+                        let stream = group_key.is_some().then_some(&mut stream);
+                        self.command(cmd, stream).await?;
+                    }
+                    None => return Ok(()), // The terminal has gone away, so there is nothing left to do.
+                }
+            }
+        }
+    }
+
+    /// Sends a message to the server.
+    ///
+    /// Messages to a room are sealed with the group key and sent straight away.
+    /// Direct messages wait in `pending` until the server sends the recipient's public key, which is asked for here.
+    async fn send_message(
+        &mut self,
+        stream: &mut Connection,
+        key: &GroupKey,
+        msg: Message,
+    ) -> Result<(), ConnectionError> {
+        match msg.to() {
+            Target::Room(room) => {
+                let frame = seal_message(key, room, &msg)?;
+                self.send_frame(stream, frame).await?; // Write the frame to the connection and flush it.
+            }
+            Target::User(user) => {
+                let user = user.clone();
+                let waiting = self.pending.entry(user.clone()).or_default();
+                waiting.push(msg);
+                if waiting.len() == 1 {
+                    stream.send(Frame::KeyRequest(user)).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the direct messages waiting on `user`'s public key, now that the server sent it.
+    /// The server could send any key, so it has to be the one remembered for them, or they are not sent at all.
+    ///
+    /// Each one is shown in the terminal once it is sent, since the server doesn't send them back to us. That also means they have no sequence number.
+    async fn send_direct(
+        &mut self,
+        stream: &mut Connection,
+        user: &str,
+        key: &[u8],
+    ) -> Result<(), ConnectionError> {
+        let msgs = self.pending.remove(user).unwrap_or_default();
+        if msgs.is_empty() {
+            return Ok(());
+        }
+        if key.is_empty() {
+            return self
+                .notice(&format!(
+                    "{user} is not online, so your message could not be sent."
+                ))
+                .await;
+        }
+        if !self.check_user(user, key).await? {
+            return Ok(()); // The messages are dropped, and the error says why.
+        }
+        let public_key = match Rsa::public_key_from_der(key) {
+            Ok(k) => k,
+            Err(e) => return self.event(NetworkEvent::Error(e.into())).await,
+        };
+        self.pin_user(user, key).await?;
+
+        for msg in msgs {
+            let msg_bytes = json!(msg).to_string().into_bytes();
+            // The key came from another user, so a bad one is only a problem for these messages.
+            let frame =
+                match Frame::seal_direct(&self.identity, &public_key, &self.user, user, &msg_bytes)
+                {
+                    Ok(frame) => frame,
+                    Err(e) => return self.event(NetworkEvent::Error(e.into())).await,
+                };
+            if self.send_frame(stream, frame).await? {
+                self.event(NetworkEvent::Message(msg)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a user's public key against the one remembered for them, reporting it if it is a different one.
+    ///
+    /// Returns whether the key can be used. A new user's key can, but is only remembered by `pin_user`.
+    async fn check_user(&self, user: &str, key: &[u8]) -> Result<bool, ConnectionError> {
+        let name = format!("{user}@{}", self.address);
+        let found = fingerprint(key);
+        match self.known_users.check(&name, &found) {
+            HostStatus::Mismatch { expected } => {
+                let e = ConnectionError::UserKeyMismatch {
+                    user: name,
+                    expected,
+                    found,
+                };
+                self.event(NetworkEvent::Error(e)).await?;
+                Ok(false)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    /// Remembers a user's key if we had none for them yet (trust on first use), and shows its fingerprint so it can be checked with them.
+    async fn pin_user(&mut self, user: &str, key: &[u8]) -> Result<(), ConnectionError> {
+        let name = format!("{user}@{}", self.address);
+        let found = fingerprint(key);
+        if self.known_users.check(&name, &found) == HostStatus::New {
+            self.known_users.add(&name, &found)?;
+            self.notice(&format!("First direct message with {user}. Their key fingerprint is {found}. Check it with them, it will be checked on every direct message from now on.")).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a frame with a message in it. A message too long to send is only reported, as the connection is still fine.
+    ///
+    /// Returns whether it was sent.
+    async fn send_frame(
+        &self,
+        stream: &mut Connection,
+        frame: Frame,
+    ) -> Result<bool, ConnectionError> {
+        match stream.send(frame).await {
+            Ok(()) => Ok(true),
+            Err(e @ ConnectionError::Protocol(ProtocolError::FrameTooLarge { .. })) => {
+                self.event(NetworkEvent::Error(e)).await?;
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Carries out a command other than sending a message.
+    ///
+    /// `stream` is the connection to the server, if the handshake is done. Without it, the rooms and the username are only remembered, and sent once it is, and typing isn't sent at all.
+    async fn command(
+        &mut self,
+        cmd: Command,
+        stream: Option<&mut Connection>,
+    ) -> Result<(), ConnectionError> {
+        let frame = match cmd {
+            Command::Join(room) if !self.rooms.contains(&room) => {
+                self.rooms.push(room.clone());
+                Frame::Join(room)
+            }
+            Command::Leave(room) if self.rooms.contains(&room) => {
+                self.rooms.retain(|r| *r != room);
+                Frame::Leave(room)
+            }
+            Command::Nick(name) if stream.is_none() => {
+                self.user = name.clone();
+                return self.event(NetworkEvent::Nick(name)).await;
+            }
+            Command::Nick(name) => Frame::Nick(name), // It changes once the server says it can.
+            Command::Who => Frame::Who,
+            Command::Typing(to) => Frame::Typing {
+                to,
+                from: self.user.clone(),
+            },
+            Command::History { room, before } => Frame::History {
+                room,
+                after: 0,
+                before,
+            },
+            _ => return Ok(()),
+        };
+        match stream {
+            Some(stream) => {
+                // Catch up on a room as soon as we join it.
+                let backfill = match &frame {
+                    Frame::Join(room) => Some(self.backfill(room)),
+                    _ => None,
+                };
+                stream.send(frame).await?;
+                if let Some(backfill) = backfill {
+                    stream.send(backfill).await?;
                 }
             }
+            None if frame == Frame::Who => self.notice("Not connected.").await?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Asks for the messages sent to `room` since the last one we saw, or for the newest ones if we haven't seen any.
+    fn backfill(&self, room: &str) -> Frame {
+        Frame::History {
+            room: room.to_owned(),
+            after: self.seen.get(room).copied().unwrap_or(0),
+            before: 0,
+        }
+    }
+
+    /// Creates the message struct for a `Command::Send`.
+    fn message(&self, to: Target, payload: &str, action: bool) -> Message {
+        if action {
+            Message::action(&self.user, to, payload)
+        } else {
+            Message::sent_to(&self.user, to, payload)
         }
     }
-    Ok(())
+
+    /// Tells the terminal that something happened.
+    async fn event(&self, event: NetworkEvent) -> Result<(), ConnectionError> {
+        self.stx.send(event).await?;
+        Ok(())
+    }
+
+    /// Shows a line from the client in the terminal.
+    async fn notice(&self, s: &str) -> Result<(), ConnectionError> {
+        self.event(NetworkEvent::Notice(s.to_owned())).await
+    }
+}
+
+/// Encrypts a message into an `ENC` frame for its room, authenticating the header and the room with it.
+fn seal_message(key: &GroupKey, room: &str, msg: &Message) -> Result<Frame, ConnectionError> {
+    let msg_bytes = json!(msg).to_string().into_bytes();
+    Ok(Frame::seal(key, room, &msg_bytes)?)
 }
 
-fn gen_rand_symm(prec: usize) -> Vec<u8> {
-    let mut key = vec![0u8; prec];
-    openssl::rand::rand_bytes(&mut key).unwrap();
-    key
+/// ### Backoff
+///
+/// The delay between reconnection attempts. It doubles after every failed attempt, up to a maximum, and goes back to the start once connected.
+#[derive(Debug)]
+struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    const MIN: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(30);
+
+    /// Gets the delay before the next attempt.
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(Self::MAX);
+        delay
+    }
+
+    /// Goes back to the shortest delay.
+    fn reset(&mut self) {
+        self.next = Self::MIN;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self { next: Self::MIN }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use tokio::net::{TcpListener, TcpStream};
+    use {
+        super::{Backoff, Client},
+        crate::{
+            address::Address,
+            event::{Command, NetworkEvent},
+            known_hosts::{HostStatus, KnownHosts},
+            prelude::ConnectionError,
+        },
+        chat_app::{
+            crypto::{fingerprint, GroupKey, Identity},
+            message::{Message, Target},
+            protocol::{Frame, FrameCodec, ProtocolError},
+        },
+        futures::{SinkExt, StreamExt},
+        openssl::rsa::Rsa,
+        serde_json::json,
+        std::{path::PathBuf, time::Duration},
+        tokio::{
+            net::{TcpListener, TcpStream},
+            sync::mpsc,
+        },
+        tokio_util::codec::Framed,
+    };
+
+    /// A client of `listener`, keeping its known hosts and users in files of their own called `name`, with the channel it takes commands from and the one it sends events to.
+    async fn client(
+        listener: &TcpListener,
+        name: &str,
+    ) -> (
+        Client,
+        PathBuf,
+        mpsc::Sender<Command>,
+        mpsc::Receiver<NetworkEvent>,
+    ) {
+        let path = std::env::temp_dir().join(format!(
+            "chat_app_known_hosts_{name}_{}",
+            std::process::id()
+        ));
+        _ = std::fs::remove_file(&path);
+        let known_hosts = KnownHosts::load(&path).unwrap();
+        let users_path = path.with_extension("users");
+        _ = std::fs::remove_file(&users_path);
+        let known_users = KnownHosts::load(&users_path).unwrap();
+        let address = Address::parse(&listener.local_addr().unwrap().to_string()).unwrap();
+        let (tx, rx) = mpsc::channel(100);
+        let (stx, srx) = mpsc::channel(100);
+        let identity = Identity::generate().unwrap();
+        let client = Client::new(
+            rx,
+            stx,
+            "alice".to_owned(),
+            address,
+            identity,
+            known_hosts,
+            known_users,
+        );
+        (client, path, tx, srx)
+    }
+
+    /// Accepts a client and does the server's side of the handshake, with the identity key of `identity` but the signature of `signer`, as a server that started at `epoch`.
+    async fn handshake(
+        listener: &TcpListener,
+        identity: &Identity,
+        signer: &Identity,
+        group_key: &GroupKey,
+        epoch: u64,
+    ) -> Framed<TcpStream, FrameCodec> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = Framed::new(stream, FrameCodec::default());
+        let Some(Ok(Frame::Pub(pub_key))) = stream.next().await else {
+            panic!("expected a PUB frame");
+        };
+        let wrapped = group_key
+            .wrap(&Rsa::public_key_from_der(&pub_key).unwrap())
+            .unwrap();
+        let signature = signer.sign(&[&pub_key[..], &wrapped].concat()).unwrap();
+        stream
+            .send(Frame::Idn {
+                key: identity.public_key().unwrap(),
+                signature,
+                epoch,
+            })
+            .await
+            .unwrap();
+        stream.send(Frame::Prv(wrapped)).await.unwrap();
+        stream
+    }
 
     #[tokio::test]
     async fn binding_test() {
@@ -196,4 +666,343 @@ mod tests {
         _ = listener.accept().await.unwrap();
         println!("{conn:?}");
     }
+
+    /// A server's key is only remembered once it has proven it holds it.
+    #[tokio::test]
+    async fn pinning_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, path, _tx, _srx) = client(&listener, "pinning").await;
+        let identity = Identity::generate().unwrap();
+        let group_key = GroupKey::generate().unwrap();
+        let found = fingerprint(&identity.public_key().unwrap());
+        let host = client.address.to_string();
+
+        // Someone else's signature doesn't count.
+        let impostor = Identity::generate().unwrap();
+        let (res, _server) = tokio::join!(
+            client.session(),
+            handshake(&listener, &identity, &impostor, &group_key, 1)
+        );
+        assert!(matches!(res, Err(ConnectionError::Handshake(_))));
+        assert_eq!(client.known_hosts.check(&host, &found), HostStatus::New);
+        assert_eq!(
+            KnownHosts::load(&path).unwrap().check(&host, &found),
+            HostStatus::New
+        );
+
+        // The real one does. The server hangs up once the handshake is done.
+        let (res, _) = tokio::join!(
+            tokio::time::timeout(Duration::from_secs(5), client.session()),
+            async {
+                let _server = handshake(&listener, &identity, &identity, &group_key, 1).await;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        );
+        assert!(matches!(res, Ok(Err(e)) if e.is_retryable()));
+        assert_eq!(
+            KnownHosts::load(&path).unwrap().check(&host, &found),
+            HostStatus::Known
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// A message that can't be decrypted is only reported, and the ones after it still arrive.
+    #[tokio::test]
+    async fn undecryptable_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, path, tx, mut srx) = client(&listener, "undecryptable").await;
+        let identity = Identity::generate().unwrap();
+        let group_key = GroupKey::generate().unwrap();
+        let session = tokio::spawn(async move { client.session().await });
+
+        let mut server = handshake(&listener, &identity, &identity, &group_key, 1).await;
+        let msg = Message::new("bob", "general", "Hi");
+        let Frame::Enc { room, sealed, .. } =
+            Frame::seal(&group_key, "general", json!(msg).to_string().as_bytes()).unwrap()
+        else {
+            panic!("expected an ENC frame");
+        };
+        for (seq, sealed) in [(1, vec![1]), (2, sealed)] {
+            let room = room.clone();
+            server.send(Frame::Enc { room, seq, sealed }).await.unwrap();
+        }
+
+        let error = loop {
+            match srx.recv().await.unwrap() {
+                NetworkEvent::Error(e) => break e,
+                NetworkEvent::Message(m) => panic!("{m:?} arrived before the error"),
+                _ => {}
+            }
+        };
+        assert_eq!(error.to_string(), "Could not decrypt a message in #general");
+        let Some(NetworkEvent::Message(m)) = srx.recv().await else {
+            panic!("expected the message after the error");
+        };
+        assert_eq!(m.id(), msg.id());
+        assert_eq!(m.seq(), Some(2));
+
+        // The session only ends when the terminal goes away.
+        drop(tx);
+        assert!(session.await.unwrap().is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// A message too long to send is reported, and the connection carries on.
+    #[tokio::test]
+    async fn too_long_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, path, tx, mut srx) = client(&listener, "too_long").await;
+        let identity = Identity::generate().unwrap();
+        let group_key = GroupKey::generate().unwrap();
+        let session = tokio::spawn(async move { client.session().await });
+        let mut server = handshake(&listener, &identity, &identity, &group_key, 1).await;
+
+        for payload in ["a".repeat(70 * 1024), "Hi".to_owned()] {
+            tx.send(Command::Send {
+                to: Target::Room("general".to_owned()),
+                payload,
+                action: false,
+            })
+            .await
+            .unwrap();
+        }
+        let (room, sealed) = loop {
+            if let Frame::Enc { room, sealed, .. } = server.next().await.unwrap().unwrap() {
+                break (room, sealed);
+            }
+        };
+        let msg: Message =
+            serde_json::from_slice(&Frame::open(&group_key, &room, &sealed).unwrap()).unwrap();
+        assert_eq!(msg.to_string(), "Hi");
+
+        let error = loop {
+            if let NetworkEvent::Error(e) = srx.recv().await.unwrap() {
+                break e;
+            }
+        };
+        assert!(matches!(
+            error,
+            ConnectionError::Protocol(ProtocolError::FrameTooLarge { .. })
+        ));
+        drop(tx);
+        assert!(session.await.unwrap().is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// After the server restarts, the client asks for everything it still has instead of what came after the last message it saw.
+    #[tokio::test]
+    async fn restart_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, path, _tx, _srx) = client(&listener, "restart").await;
+        let identity = Identity::generate().unwrap();
+        let group_key = GroupKey::generate().unwrap();
+        client.rooms.push("general".to_owned());
+        client.seen.insert("general".to_owned(), 5);
+
+        let mut backfills = Vec::new();
+        for epoch in [1, 1, 2] {
+            let (_, frame) = tokio::join!(client.session(), async {
+                let mut server =
+                    handshake(&listener, &identity, &identity, &group_key, epoch).await;
+                loop {
+                    let frame = server.next().await.unwrap().unwrap();
+                    if let Frame::History { .. } = frame {
+                        break frame;
+                    }
+                }
+            });
+            backfills.push(frame);
+        }
+        let history = |after| Frame::History {
+            room: "general".to_owned(),
+            after,
+            before: 0,
+        };
+        assert_eq!(backfills, [history(5), history(5), history(0)]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Direct messages are signed, and a user's key is remembered the first time and checked every time after.
+    #[tokio::test]
+    async fn direct_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, path, tx, mut srx) = client(&listener, "direct").await;
+        let identity = Identity::generate().unwrap();
+        let group_key = GroupKey::generate().unwrap();
+        let alice_key = client.identity.public_key().unwrap();
+        let users_path = path.with_extension("users");
+        let bob_name = format!("bob@{}", client.address);
+        let session = tokio::spawn(async move { client.session().await });
+        let mut server = handshake(&listener, &identity, &identity, &group_key, 1).await;
+        let bob = Identity::generate().unwrap();
+        let mallory = Identity::generate().unwrap();
+
+        // Alice's message to Bob is sealed to the key the server says is his, and signed with hers.
+        let send = |payload: &str| Command::Send {
+            to: Target::User("bob".to_owned()),
+            payload: payload.to_owned(),
+            action: false,
+        };
+        tx.send(send("Psst")).await.unwrap();
+        loop {
+            if server.next().await.unwrap().unwrap() == Frame::KeyRequest("bob".to_owned()) {
+                break;
+            }
+        }
+        let key = bob.public_key().unwrap();
+        server
+            .send(Frame::Key {
+                user: "bob".to_owned(),
+                key,
+            })
+            .await
+            .unwrap();
+        let Some(Ok(Frame::Direct {
+            peer,
+            sender_key,
+            wrapped,
+            sealed,
+            signature,
+            ..
+        })) = server.next().await
+        else {
+            panic!("expected a DMS frame");
+        };
+        assert_eq!((peer.as_str(), &sender_key), ("bob", &alice_key));
+        let bob_rsa = bob.rsa().unwrap();
+        let opened = Frame::open_direct(
+            &bob_rsa,
+            "alice",
+            "bob",
+            &sender_key,
+            &wrapped,
+            &sealed,
+            &signature,
+        );
+        let msg: Message = serde_json::from_slice(&opened.unwrap()).unwrap();
+        assert_eq!(msg.to_string(), "Psst");
+        let fp = fingerprint(&bob.public_key().unwrap());
+        assert_eq!(
+            KnownHosts::load(&users_path).unwrap().check(&bob_name, &fp),
+            HostStatus::Known
+        );
+
+        // Bob's answer arrives, but not one from Mallory claiming to be Bob.
+        let alice_pub = Rsa::public_key_from_der(&alice_key).unwrap();
+        for (seq, signer) in [(1, &mallory), (2, &bob)] {
+            let msg = Message::direct("bob", "alice", "Hi");
+            let sealed = Frame::seal_direct(
+                signer,
+                &alice_pub,
+                "bob",
+                "alice",
+                json!(msg).to_string().as_bytes(),
+            );
+            let Frame::Direct {
+                sender_key,
+                wrapped,
+                sealed,
+                signature,
+                ..
+            } = sealed.unwrap()
+            else {
+                panic!("expected a DMS frame");
+            };
+            let peer = "bob".to_owned();
+            let frame = Frame::Direct {
+                peer,
+                seq,
+                sender_key,
+                wrapped,
+                sealed,
+                signature,
+            };
+            server.send(frame).await.unwrap();
+        }
+        let mut errors = 0;
+        let msg = loop {
+            match srx.recv().await.unwrap() {
+                NetworkEvent::Error(ConnectionError::UserKeyMismatch { user, .. }) => {
+                    assert_eq!(user, bob_name);
+                    errors += 1;
+                }
+                NetworkEvent::Message(m) if m.from() == "bob" => break m,
+                _ => {}
+            }
+        };
+        assert_eq!((errors, msg.seq()), (1, Some(2)));
+
+        // Nothing is sent to a key that isn't the one remembered for Bob.
+        tx.send(send("Still there?")).await.unwrap();
+        loop {
+            if server.next().await.unwrap().unwrap() == Frame::KeyRequest("bob".to_owned()) {
+                break;
+            }
+        }
+        let key = mallory.public_key().unwrap();
+        server
+            .send(Frame::Key {
+                user: "bob".to_owned(),
+                key,
+            })
+            .await
+            .unwrap();
+        loop {
+            match srx.recv().await.unwrap() {
+                NetworkEvent::Error(ConnectionError::UserKeyMismatch { .. }) => break,
+                NetworkEvent::Message(m) => panic!("{m:?} was sent to the wrong key"),
+                _ => {}
+            }
+        }
+        drop(tx);
+        assert!(session.await.unwrap().is_ok());
+        assert!(server.next().await.is_none());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&users_path).unwrap();
+    }
+
+    /// The client keeps asking for the next page of what it missed for as long as the server says there is one.
+    #[tokio::test]
+    async fn catch_up_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, path, tx, _srx) = client(&listener, "catch_up").await;
+        let identity = Identity::generate().unwrap();
+        let group_key = GroupKey::generate().unwrap();
+        client.rooms.push("general".to_owned());
+        client.seen.insert("general".to_owned(), 5);
+        let session = tokio::spawn(async move { client.session().await });
+
+        let mut server = handshake(&listener, &identity, &identity, &group_key, 1).await;
+        let history = |room: &str, after| Frame::History {
+            room: room.to_owned(),
+            after,
+            before: 0,
+        };
+        let mut requests = Vec::new();
+        while requests.len() < 2 {
+            if let Frame::History { room, after, .. } = server.next().await.unwrap().unwrap() {
+                requests.push(history(&room, after));
+                if after == 5 {
+                    // More than a page was missed, and another room the client isn't in has more too.
+                    server.send(history("random", 55)).await.unwrap();
+                    server.send(history("general", 55)).await.unwrap();
+                }
+            }
+        }
+        assert_eq!(requests, [history("general", 5), history("general", 55)]);
+
+        drop(tx);
+        assert!(session.await.unwrap().is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn backoff_test() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<_> = (0..7).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
 }
@@ -0,0 +1,187 @@
+use {
+    crate::rooms::{parse_room, parse_user},
+    std::fmt::Display,
+};
+
+/// The list of commands shown by `/help`.
+pub const HELP: &str = "\
+/join <room>          Join a room, or switch to it
+/msg <user> [message] Open a direct conversation, and send it a message
+/me <action>          Send an action, shown as * you <action>
+/nick <name>          Change your username
+/who                  List who is online
+/clear                Clear the messages shown in this room
+/help                 Show this list
+/quit                 Leave the chat
+Start a message with // to send it with a single / in front.
+Messages can have *bold*, _italic_ and `code` in them, and blocks of code between lines of ```.
+Shift+Enter starts a new line.";
+
+/// ### Input
+///
+/// What the user typed in the input box.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    /// A message to send to the current room or conversation.
+    Text(String),
+    /// A slash command.
+    Command(SlashCommand),
+}
+
+/// ### Slash Command
+///
+/// A command typed in the input box, starting with a `/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SlashCommand {
+    Join(String),
+    Msg { user: String, text: Option<String> },
+    Me(String),
+    Nick(String),
+    Who,
+    Clear,
+    Help,
+    Quit,
+}
+
+/// An error from a slash command that could not be understood.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SlashError {
+    /// There is no command with this name.
+    Unknown(String),
+    /// The command was given the wrong arguments. Holds how it should be used.
+    Usage(&'static str),
+    /// The argument is not a valid room name.
+    InvalidRoom(String),
+    /// The argument is not a valid username.
+    InvalidUser(String),
+}
+
+impl Display for SlashError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unknown(cmd) => write!(
+                f,
+                "Unknown command /{cmd}. Type /help for the list of commands."
+            ),
+            Self::Usage(usage) => write!(f, "Usage: {usage}"),
+            Self::InvalidRoom(room) => write!(f, "'{room}' is not a valid room name."),
+            Self::InvalidUser(user) => write!(f, "'{user}' is not a valid username."),
+        }
+    }
+}
+
+/// Parses what the user typed in the input box.
+///
+/// Anything starting with a `/` is a command, except `//`, which sends the rest of the text with a single `/` in front.
+pub fn parse(line: &str) -> Result<Input, SlashError> {
+    let Some(cmd) = line.strip_prefix('/') else {
+        return Ok(Input::Text(line.to_owned()));
+    };
+    if cmd.starts_with('/') {
+        return Ok(Input::Text(cmd.to_owned()));
+    }
+
+    // The name of the command, and everything after it.
+    let (name, args) = match cmd.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (cmd, ""),
+    };
+    let cmd = match name.to_lowercase().as_str() {
+        "join" => {
+            let room = one_arg(args, "/join <room>")?;
+            SlashCommand::Join(parse_room(room).ok_or(SlashError::InvalidRoom(room.to_owned()))?)
+        }
+        "msg" => {
+            let (user, text) = match args.split_once(char::is_whitespace) {
+                Some((user, text)) => (user, Some(text.trim().to_owned())),
+                None if !args.is_empty() => (args, None),
+                None => return Err(SlashError::Usage("/msg <user> [message]")),
+            };
+            SlashCommand::Msg {
+                user: parse_user(user).ok_or(SlashError::InvalidUser(user.to_owned()))?,
+                text,
+            }
+        }
+        "me" if !args.is_empty() => SlashCommand::Me(args.to_owned()),
+        "me" => return Err(SlashError::Usage("/me <action>")),
+        "nick" => {
+            let user = one_arg(args, "/nick <name>")?;
+            SlashCommand::Nick(parse_user(user).ok_or(SlashError::InvalidUser(user.to_owned()))?)
+        }
+        "who" => SlashCommand::Who,
+        "clear" => SlashCommand::Clear,
+        "help" => SlashCommand::Help,
+        "quit" => SlashCommand::Quit,
+        _ => return Err(SlashError::Unknown(name.to_owned())),
+    };
+    Ok(Input::Command(cmd))
+}
+
+/// Gets the only argument of a command, or how it should be used if there isn't exactly one.
+fn one_arg<'a>(args: &'a str, usage: &'static str) -> Result<&'a str, SlashError> {
+    match args.split_whitespace().count() {
+        1 => Ok(args),
+        _ => Err(SlashError::Usage(usage)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Input, SlashCommand, SlashError};
+
+    fn cmd(line: &str) -> SlashCommand {
+        match parse(line) {
+            Ok(Input::Command(cmd)) => cmd,
+            res => panic!("{line}: {res:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(parse("hello").unwrap(), Input::Text("hello".to_owned()));
+        assert_eq!(parse("//shrug").unwrap(), Input::Text("/shrug".to_owned()));
+
+        assert_eq!(cmd("/join #rust"), SlashCommand::Join("rust".to_owned()));
+        assert_eq!(
+            cmd("/msg @bob  hi there "),
+            SlashCommand::Msg {
+                user: "bob".to_owned(),
+                text: Some("hi there".to_owned())
+            }
+        );
+        assert_eq!(
+            cmd("/msg bob"),
+            SlashCommand::Msg {
+                user: "bob".to_owned(),
+                text: None
+            }
+        );
+        assert_eq!(cmd("/me waves"), SlashCommand::Me("waves".to_owned()));
+        assert_eq!(cmd("/NICK alice"), SlashCommand::Nick("alice".to_owned()));
+        assert_eq!(cmd("/who"), SlashCommand::Who);
+        assert_eq!(cmd("/clear"), SlashCommand::Clear);
+        assert_eq!(cmd("/help"), SlashCommand::Help);
+        assert_eq!(cmd("/quit"), SlashCommand::Quit);
+    }
+
+    #[test]
+    fn error_test() {
+        assert_eq!(
+            parse("/dance"),
+            Err(SlashError::Unknown("dance".to_owned()))
+        );
+        assert_eq!(parse("/"), Err(SlashError::Unknown("".to_owned())));
+        assert!(matches!(parse("/join"), Err(SlashError::Usage(_))));
+        assert!(matches!(parse("/join a b"), Err(SlashError::Usage(_))));
+        assert!(matches!(parse("/msg"), Err(SlashError::Usage(_))));
+        assert!(matches!(parse("/me"), Err(SlashError::Usage(_))));
+        assert_eq!(
+            parse("/join #"),
+            Err(SlashError::InvalidRoom("#".to_owned()))
+        );
+        assert_eq!(
+            parse("/nick al\u{7}ice"),
+            Err(SlashError::InvalidUser("al\u{7}ice".to_owned()))
+        );
+    }
+}
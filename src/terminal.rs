@@ -1,166 +1,441 @@
 use {
-    crate::{message::Message, prelude::ConnectionError},
+    crate::{
+        address::Address,
+        event::{Command, ConnectionState, NetworkEvent},
+        history::History,
+        message_list::WHEEL_LINES,
+        prelude::{error_chain, ConnectionError},
+        rooms::{parse_room, parse_user, NoticeKind, Room, Rooms},
+        roster::Roster,
+        slash::{self, SlashCommand, HELP},
+        theme::Theme,
+    },
+    chat_app::{
+        message::Target,
+        protocol::{Presence, DEFAULT_ROOM},
+    },
     crossterm::{
         event::{
-            self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind,
-            KeyModifiers,
+            DisableMouseCapture, EnableMouseCapture, Event, EventStream, KeyCode, KeyEvent,
+            KeyEventKind, KeyModifiers, MouseEvent, MouseEventKind,
         },
         execute,
         terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
     },
+    futures::StreamExt,
     ratatui::{
         backend::CrosstermBackend,
         layout::{Constraint, Direction, Layout},
-        style::{Color, Style},
-        widgets::{Block, BorderType, Borders},
+        style::{Modifier, Style},
+        text::Span,
+        widgets::{List, ListItem, ListState, Paragraph},
         Frame, Terminal,
     },
-    std::io::Stdout,
-    tokio::sync::mpsc::channel,
+    std::{
+        io::Stdout,
+        time::{Duration, Instant},
+    },
+    tokio::{
+        sync::mpsc::{channel, Sender},
+        time::MissedTickBehavior,
+    },
     tui_textarea::{Input, Key, TextArea},
 };
 
+/// How often the screen is redrawn when nothing else is happening.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+/// The width of the list of rooms on the left.
+const SIDEBAR_WIDTH: u16 = 20;
+/// The width of the list of users next to the messages.
+const USERS_WIDTH: u16 = 20;
+/// How often others are told we are still typing. It has to be shorter than `roster::TYPING_TIMEOUT`, or we'd flicker on their screens.
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
 /// ### Terminal update loop.
 ///
 /// Both the `sender_loop` and `reciever_loop` start from here.
 ///
 /// Two sets of senders and recievers are made. One Sender is set to the `reciever_loop`, and one Reciever is passed to the `sender_loop`
-pub async fn terminal_loop(user: String, ip: String) -> Result<(), ConnectionError> {
-    enable_raw_mode().unwrap(); // Enable raw mode so we can detect each keystroke.
+///
+/// Messages are shown with the time they were sent in the local timezone, in `time_format`.
+/// If there is a `history`, every message is kept in it, and the last ones are reloaded into each room when it is opened.
+/// Everything is drawn in the colors of `theme`.
+///
+/// The terminal is always put back to normal before returning, so any error can be shown to the user afterwards.
+pub async fn terminal_loop(
+    user: String,
+    address: Address,
+    time_format: String,
+    history: Option<History>,
+    theme: Theme,
+) -> Result<(), ConnectionError> {
+    enable_raw_mode()?; // Enable raw mode so we can detect each keystroke.
     let mut stdout = std::io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture).unwrap(); // Create an alternate screen an swap to it
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend).unwrap(); // Create the crossterm terminal app
+    // Create an alternate screen an swap to it, then create the crossterm terminal app
+    let terminal = execute!(stdout, EnterAlternateScreen, EnableMouseCapture)
+        .and_then(|_| Terminal::new(CrosstermBackend::new(stdout)));
+    let mut terminal = match terminal {
+        Ok(t) => t,
+        Err(e) => {
+            _ = disable_raw_mode();
+            return Err(e.into());
+        }
+    };
 
+    let res = run_loop(
+        &mut terminal,
+        user,
+        address,
+        &time_format,
+        history.as_ref(),
+        &theme,
+    )
+    .await;
+
+    // Undo the alternate screen and raw mode.
+    if let Err(e) = leave_terminal(terminal) {
+        println!("{e}");
+    };
+
+    res
+}
+
+/// ### Main loop of the terminal.
+///
+/// Spawns the `sender_loop`, then handles key presses and messages from it until the user leaves or something goes wrong.
+///
+/// Input starting with a `/` is a slash command (see `slash::HELP`), anything else is sent to the current room or conversation.
+///
+/// PageUp, PageDown and the mouse wheel scroll through the messages, and scrolling to the top of a room asks the server for older ones.
+///
+/// Rooms are switched with Alt+Up/Alt+Down or Alt+1-9. Alt+J joins the room named in the input box, Alt+D opens a direct conversation with the user named in it,
+/// and Alt+L leaves the current room or closes the current conversation.
+async fn run_loop(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    mut user: String,
+    address: Address,
+    time_format: &str,
+    history: Option<&History>,
+    theme: &Theme,
+) -> Result<(), ConnectionError> {
     // Create two sets of channels
-    let (stx, srx) = channel::<String>(25); // Send the message from the terminal to the sender
-    let (sstx, mut ssrx) = channel::<String>(25); // Send from the Sender to the Reciever. (The Sender handles both incoming and outgoing messages)
+    let (stx, srx) = channel::<Command>(25); // Send commands from the terminal to the sender
+    let (sstx, mut ssrx) = channel::<NetworkEvent>(25); // Send events from the Sender to the terminal. (The Sender handles both incoming and outgoing messages)
 
     // Spawn the sender loop
-    let mut sender = tokio::spawn(async {
-        match crate::sender::sender_loop(srx, sstx, user, ip).await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
-        }
-    });
+    let mut sender = tokio::spawn(crate::sender::sender_loop(
+        srx,
+        sstx,
+        user.clone(),
+        address.clone(),
+    ));
 
     // Create the TextArea where the user will be inputting his text. Add a border around it
     let mut text_input = TextArea::default();
-    text_input.set_block(
-        Block::default()
-            .borders(Borders::ALL)
-            .border_type(BorderType::Rounded)
-            .title("Input"),
-    );
+    text_input.set_block(theme.block().title("Input"));
+    text_input.set_style(Style::default().fg(theme.text));
 
-    // Create the TextArea where the messages will be displayed. Add a border around it, and hide the cursor.
-    let mut text_messages = TextArea::default();
-    text_messages.set_block(
-        Block::default()
-            .title("Messages")
-            .borders(Borders::ALL)
-            .border_style(Style::default().fg(Color::White))
-            .border_type(BorderType::Rounded),
-    );
-    text_messages.set_cursor_style(Style::default().fg(Color::Black));
+    // Everyone starts off in the default room.
+    let mut rooms = Rooms::new(DEFAULT_ROOM);
+    reload(rooms.current_mut(), history);
+    stx.send(Command::Join(DEFAULT_ROOM.to_owned())).await?;
+
+    // Who is online.
+    let mut roster = Roster::default();
+
+    // Where and when we last told others we are typing.
+    let mut typing_sent: Option<(Target, Instant)> = None;
+
+    // Keyboard input, network events and redraw ticks are all waited on together, so nothing holds up anything else.
+    let mut input = EventStream::new();
+    let mut redraw = tokio::time::interval(REDRAW_INTERVAL);
+    redraw.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
     // Main loop
     loop {
         // Draw the ui for the terminal
-        if let Err(e) = terminal.draw(|f| draw_ui(f, &text_input, &text_messages)) {
-            return Err(ConnectionError::new(&e.kind().to_string()));
-        };
-        let frame = terminal.get_frame();
-
-        // Check for key events. Handle them appropriately.
-        // If its an Enter without SHIFT, send the message to the Sender.
-        // If it's Enter with SHIFT, add a newline.
-        // Anything else gets typed into the TextArea.
-        match event::read() {
-            Ok(Event::Key(KeyEvent {
-                code: KeyCode::Enter,
-                modifiers,
-                kind: KeyEventKind::Press,
-                ..
-            })) if !modifiers.contains(KeyModifiers::SHIFT) => {
-                let s = text_input.lines().join("\n");
-                stx.send(s).await.unwrap();
-                while text_input.delete_char() {}
-            }
-            Ok(Event::Key(
-                k @ KeyEvent {
+        terminal.draw(|f| {
+            draw_ui(
+                f,
+                &text_input,
+                &mut rooms,
+                &roster,
+                time_format,
+                &user,
+                theme,
+            )
+        })?;
+
+        tokio::select! {
+            // Check for key events. Handle them appropriately.
+            // If its an Enter without SHIFT, send the message to the Sender.
+            // If it's Enter with SHIFT, add a newline.
+            // Anything else gets typed into the TextArea.
+            ev = input.next() => match ev {
+                Some(Ok(Event::Key(KeyEvent {
+                    code: KeyCode::Enter,
+                    modifiers,
+                    kind: KeyEventKind::Press,
+                    ..
+                }))) if !modifiers.contains(KeyModifiers::SHIFT) => {
+                    let line = text_input.lines().join("\n");
+                    while text_input.delete_char() {}
+                    typing_sent = None; // Start telling others again as soon as we type the next message.
+                    match slash::parse(&line) {
+                        Ok(slash::Input::Text(payload)) => {
+                            let to = rooms.current().target.clone();
+                            stx.send(Command::Send { to, payload, action: false }).await?;
+                        }
+                        Ok(slash::Input::Command(cmd)) => {
+                            if run_command(cmd, &mut rooms, &stx, history).await? {
+                                break;
+                            }
+                        }
+                        Err(e) => rooms.current_mut().notice(NoticeKind::System, &e.to_string()),
+                    }
+                }
+                Some(Ok(Event::Key(KeyEvent {
                     code,
+                    modifiers: KeyModifiers::ALT,
                     kind: KeyEventKind::Press,
                     ..
+                }))) if is_room_key(code) => match code {
+                    KeyCode::Up => rooms.prev(),
+                    KeyCode::Down => rooms.next(),
+                    KeyCode::Char(c @ '1'..='9') => rooms.select(c as usize - '1' as usize),
+                    KeyCode::Char('j') => match parse_room(&text_input.lines().join("")) {
+                        Some(room) => {
+                            run_command(SlashCommand::Join(room), &mut rooms, &stx, history).await?;
+                            while text_input.delete_char() {}
+                        }
+                        None => rooms.current_mut().notice(NoticeKind::System, "Type the name of a room to join in the input box first."),
+                    },
+                    KeyCode::Char('d') => match parse_user(&text_input.lines().join("")) {
+                        Some(user) => {
+                            run_command(SlashCommand::Msg { user, text: None }, &mut rooms, &stx, history).await?;
+                            while text_input.delete_char() {}
+                        }
+                        None => rooms.current_mut().notice(NoticeKind::System, "Type the name of a user to message in the input box first."),
+                    },
+                    KeyCode::Char('l') => match rooms.leave_current() {
+                        Some(Target::Room(room)) => stx.send(Command::Leave(room)).await?,
+                        Some(Target::User(_)) => {} // The server doesn't know about direct conversations.
+                        None => rooms.current_mut().notice(NoticeKind::System, "You can't leave your only room."),
+                    },
+                    _ => {}
                 },
-            )) if code != KeyCode::Esc => {
-                text_input.input(to_input(k));
-            }
-            Ok(Event::Key(KeyEvent {
-                code: KeyCode::Esc, ..
-            })) => break,
-            Err(_) => panic!("An error has occured upon reading input"),
-            _ => {}
-        }
+                Some(Ok(Event::Key(KeyEvent {
+                    code: KeyCode::Esc, ..
+                }))) => break,
+                Some(Ok(Event::Key(KeyEvent {
+                    code: KeyCode::PageUp,
+                    kind: KeyEventKind::Press,
+                    ..
+                }))) => {
+                    rooms.current_mut().view.page_up();
+                    fetch_older(rooms.current_mut(), &stx).await?;
+                }
+                Some(Ok(Event::Key(KeyEvent {
+                    code: KeyCode::PageDown,
+                    kind: KeyEventKind::Press,
+                    ..
+                }))) => rooms.current_mut().view.page_down(),
+                Some(Ok(Event::Mouse(MouseEvent { kind: MouseEventKind::ScrollUp, .. }))) => {
+                    rooms.current_mut().view.scroll_up(WHEEL_LINES);
+                    fetch_older(rooms.current_mut(), &stx).await?;
+                }
+                Some(Ok(Event::Mouse(MouseEvent { kind: MouseEventKind::ScrollDown, .. }))) => {
+                    rooms.current_mut().view.scroll_down(WHEEL_LINES);
+                }
+                Some(Ok(Event::Key(k @ KeyEvent {
+                    kind: KeyEventKind::Press,
+                    ..
+                }))) => {
+                    if text_input.input(to_input(k)) {
+                        // Let the conversation know we are typing, but not on every key, and not for slash commands.
+                        let to = &rooms.current().target;
+                        let typing = text_input.lines().iter().any(|l| !l.is_empty()) && !text_input.lines()[0].starts_with('/');
+                        let due = match &typing_sent {
+                            Some((sent_to, at)) => sent_to != to || at.elapsed() >= TYPING_INTERVAL,
+                            None => true,
+                        };
+                        if typing && due {
+                            // It doesn't matter if it gets dropped when the sender is busy.
+                            _ = stx.try_send(Command::Typing(to.clone()));
+                            typing_sent = Some((to.clone(), Instant::now()));
+                        }
+                    }
+                }
+                Some(Ok(_)) => {} // Resizes and the like only need a redraw. The messages are wrapped to fit on every one.
+                Some(Err(e)) => return Err(e.into()),
+                None => break, // No more input is coming.
+            },
+            // Try and recieve an event
+            Some(event) = ssrx.recv() => match event {
+                NetworkEvent::Message(m) => {
+                    let conversation = m.conversation(&user);
+                    roster.stopped(&conversation, m.from());
+
+                    // A new direct conversation starts off with what we said in it before.
+                    if let Some(room) = rooms.open(&conversation) {
+                        reload(room, history);
+                    }
 
-        // Check if we recieve something from the Reciever for 1 millisecond. If not, continue the loop.
-        tokio::select! {
-            // Try and recieve a message
-            Some(s) = ssrx.recv() => {
-                if s == "C" {
-                    if let Err(e) = leave_terminal(terminal) {
-                        println!("{e}");
+                    // Messages for a room that was just left, and copies of ones already shown, are dropped.
+                    if let Some((room, _)) = rooms.recieve(&conversation, m.clone()) {
+                        if let Some(Err(e)) = history.map(|h| h.append(&conversation, &m)) {
+                            room.notice(NoticeKind::Error, &error_chain(&e));
+                        }
+                    }
+                }
+                NetworkEvent::State(state) => {
+                    if let ConnectionState::Disconnected { .. } = state {
+                        roster.clear(); // We'll be told who's here again once we reconnect.
+                    }
+                    let s = match state {
+                        ConnectionState::Connecting => format!("Connecting to {address}…"),
+                        ConnectionState::Connected { restarted } => {
+                            rooms.connected(restarted);
+                            format!("Connected to {address}.")
+                        }
+                        ConnectionState::Disconnected { reason, retry_in } => format!(
+                            "Connection lost: {reason}\nReconnecting in {}s…",
+                            retry_in.as_secs()
+                        ),
                     };
-                    return Err(ConnectionError::new("The connection was forcibly closed by the server"));
+                    rooms.current_mut().notice(NoticeKind::System, &s);
                 }
-                let Ok(m) = serde_json::from_str::<Message>(&s) else {
-                    return Err(ConnectionError::new(&format!("Incoming message '{s}' was unparseable")));
-                };
-                let header = m.get_header();
-                text_messages.insert_str(header);
-                text_messages.insert_newline();
-
-                let mut msg = format!("{m}");
-                let len = msg.len();
-                let mut counter = 0;
-                for idx in 0..len {
-                    // Handle if the statement is longer than the width of the TextArea, and insert newlines as appropriate
-                    if (idx % (frame.size().width - 2) as usize) == 0 && idx > 0 {
-                        msg.insert(idx + counter, '\n');
-                        counter += 1;
+                NetworkEvent::Error(e) => rooms.current_mut().notice(NoticeKind::Error, &error_chain(&e)),
+                NetworkEvent::Notice(s) => rooms.current_mut().notice(NoticeKind::System, &s),
+                NetworkEvent::Nick(name) => {
+                    if name != user {
+                        rooms.current_mut().notice(NoticeKind::System, &format!("You are now known as {name}."));
+                        user = name;
                     }
                 }
-
-                // Display the recieved message
-                for (idx, line) in msg.lines().enumerate() {
-                    if idx == 0 {
-                        text_messages.insert_str(line);
-                    } else {
-                        text_messages.insert_str(format!("{line}"));
+                NetworkEvent::Users(users) => {
+                    rooms.current_mut().notice(NoticeKind::System, &format!("Online: {}", users.join(", ")));
+                    roster.set(users);
+                }
+                NetworkEvent::Typing { user, conversation } => roster.typing(conversation, user, Instant::now()),
+                NetworkEvent::Presence(presence) => {
+                    roster.apply(&presence);
+
+                    // We already know when we join or change our own name.
+                    let own = matches!(&presence, Presence::Joined(u) | Presence::Renamed { to: u, .. } if *u == user);
+                    if !own {
+                        rooms.current_mut().notice(NoticeKind::System, &presence.to_string());
                     }
-                    text_messages.insert_newline();
                 }
-                text_messages.insert_newline();
             },
-            Ok(e) = &mut sender => {
-                if let Err(e) = leave_terminal(terminal) {
-                    println!("{e}");
+            res = &mut sender => {
+                // The sender only stops on its own if something went wrong that reconnecting can't fix.
+                return match res {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err(e)) => Err(e),
+                    Err(e) => Err(ConnectionError::Io(e.into())),
                 };
-                return e;
             }
-            // Wait for a millisecond. Continue the loop if this elapses.
-            _ = tokio::time::sleep(std::time::Duration::from_millis(1)) => {}
+            // Redraw every so often even if nothing happened.
+            _ = redraw.tick() => {}
         }
     }
 
-    // Undo the alternate screen and raw mode.
-    if let Err(e) = leave_terminal(terminal) {
-        println!("{e}");
-    };
+    Ok(())
+}
 
+/// # Run Command
+///
+/// Parameters:
+/// ```text
+/// cmd: SlashCommand // The command typed by the user
+/// rooms: &mut Rooms // The joined rooms and direct conversations
+/// stx: &Sender<Command> // The channel to the sender
+/// history: Option<&History> // The history to reload new rooms from
+/// ```
+/// Carries out a slash command, either here or by passing it on to the sender.
+///
+/// Returns true if the user asked to quit.
+async fn run_command(
+    cmd: SlashCommand,
+    rooms: &mut Rooms,
+    stx: &Sender<Command>,
+    history: Option<&History>,
+) -> Result<bool, ConnectionError> {
+    match cmd {
+        SlashCommand::Join(room) => {
+            if rooms.join(&Target::Room(room.clone())) {
+                reload(rooms.current_mut(), history);
+                stx.send(Command::Join(room)).await?;
+            }
+        }
+        SlashCommand::Msg { user, text } => {
+            let to = Target::User(user);
+            if rooms.join(&to) {
+                reload(rooms.current_mut(), history);
+            }
+            if let Some(payload) = text {
+                stx.send(Command::Send {
+                    to,
+                    payload,
+                    action: false,
+                })
+                .await?;
+            }
+        }
+        SlashCommand::Me(payload) => {
+            let to = rooms.current().target.clone();
+            stx.send(Command::Send {
+                to,
+                payload,
+                action: true,
+            })
+            .await?;
+        }
+        SlashCommand::Nick(name) => stx.send(Command::Nick(name)).await?,
+        SlashCommand::Who => stx.send(Command::Who).await?,
+        SlashCommand::Clear => rooms.current_mut().clear(),
+        SlashCommand::Help => rooms.current_mut().notice(NoticeKind::Help, HELP),
+        SlashCommand::Quit => return Ok(true),
+    }
+    Ok(false)
+}
+
+/// # Fetch Older
+///
+/// Parameters:
+/// ```text
+/// room: &mut Room // The room the user is looking at
+/// stx: &Sender<Command> // The channel to the sender
+/// ```
+/// Asks the server for the messages before the ones in the room, once the user has scrolled to the top of it, unless they were asked for already.
+async fn fetch_older(room: &mut Room, stx: &Sender<Command>) -> Result<(), ConnectionError> {
+    if let (true, Target::Room(name)) = (room.view.at_top(), room.target.clone()) {
+        if let Some(before) = room.older() {
+            stx.send(Command::History { room: name, before }).await?;
+        }
+    }
     Ok(())
 }
 
+/// # Reload
+///
+/// Parameters:
+/// ```text
+/// room: &mut Room // A room that was just opened
+/// history: Option<&History> // The history to reload it from, if there is one
+/// ```
+/// Puts the last messages kept in the history of the room back in it.
+fn reload(room: &mut Room, history: Option<&History>) {
+    let Some(history) = history else {
+        return;
+    };
+    match history.load(&room.target) {
+        Ok(msgs) => room.load(msgs),
+        Err(e) => room.notice(NoticeKind::Error, &error_chain(&e)),
+    }
+}
+
 /// # Draw UI
 ///
 /// Parameters
@@ -168,26 +443,103 @@ pub async fn terminal_loop(user: String, ip: String) -> Result<(), ConnectionErr
 /// ```
 /// f: Frame // The frame we are rendering the widgets from
 /// ta: &TextArea // The TextArea where the user is typing
-/// msg: &TextArea // The TextArea where the messages are
+/// rooms: &mut Rooms // The joined rooms and direct conversations. The messages of the current one are shown, and its view is scrolled
+/// roster: &Roster // Who is online, and who is typing in the current room
+/// time_format: &str // How to show the time a message was sent
+/// user: &str // Who we are. Our own messages stand out from the others
+/// theme: &Theme // The colors to draw everything in
 /// ```
-fn draw_ui(f: &mut Frame, ta: &TextArea, msg: &TextArea) {
-    let msg_widget = msg.widget();
+fn draw_ui(
+    f: &mut Frame,
+    ta: &TextArea,
+    rooms: &mut Rooms,
+    roster: &Roster,
+    time_format: &str,
+    user: &str,
+    theme: &Theme,
+) {
     let widget = ta.widget();
 
+    // The list of rooms on the left, with how many unread messages each one has.
+    let items: Vec<_> = rooms
+        .iter()
+        .map(|r| match r.unread {
+            0 => ListItem::new(r.target.to_string()),
+            n => ListItem::new(format!("{} ({n})", r.target)),
+        })
+        .collect();
+    let sidebar = List::new(items)
+        .block(theme.block().title("Rooms"))
+        .style(Style::default().fg(theme.text))
+        .highlight_style(
+            Style::default()
+                .fg(theme.selected)
+                .add_modifier(Modifier::REVERSED),
+        );
+    let mut state = ListState::default().with_selected(Some(rooms.current_index()));
+
+    // Who is typing, on the line under the messages.
+    let status = roster
+        .typing_status(&rooms.current().target, Instant::now())
+        .unwrap_or_default();
+    let status = Paragraph::new(status).style(
+        Style::default()
+            .fg(theme.status)
+            .add_modifier(Modifier::ITALIC),
+    );
+
+    // The list of users next to the messages.
+    let users = List::new(
+        roster
+            .iter()
+            .map(|u| {
+                ListItem::new(Span::styled(
+                    u.as_str(),
+                    Style::default().fg(theme.user_color(u)),
+                ))
+            })
+            .collect::<Vec<_>>(),
+    )
+    .block(theme.block().title(format!("Users ({})", roster.len())));
+
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Length(SIDEBAR_WIDTH), Constraint::Min(0)].as_ref())
+        .split(f.size());
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([Constraint::Percentage(80), Constraint::Percentage(20)].as_ref())
-        .split(f.size());
-
-    f.render_widget(msg_widget, chunks[0]);
+        .split(columns[1]);
+    let top = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Min(0), Constraint::Length(USERS_WIDTH)].as_ref())
+        .split(chunks[0]);
+    let messages = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(0), Constraint::Length(1)].as_ref())
+        .split(top[0]);
+
+    f.render_stateful_widget(sidebar, columns[0], &mut state);
+    let (msg_widget, view) = rooms.current_mut().widget(time_format, user, theme);
+    f.render_stateful_widget(msg_widget, messages[0], view);
+    f.render_widget(status, messages[1]);
+    f.render_widget(users, top[1]);
     f.render_widget(widget, chunks[1]);
 }
 
+/// Whether an Alt+`code` key press is one of the room keys handled by `run_loop`.
+fn is_room_key(code: KeyCode) -> bool {
+    matches!(
+        code,
+        KeyCode::Up | KeyCode::Down | KeyCode::Char('1'..='9' | 'j' | 'd' | 'l')
+    )
+}
+
 /// # To Input
 ///
 /// Parameters:
 /// ```
-/// key: KeyEvent // The key event gotten from crossterm's event::read()
+/// key: KeyEvent // The key event gotten from crossterm's EventStream
 /// ```
 /// Converts a given KeyEvent into an Input that tui_textarea can read.
 /// ### This is a copy + paste of tui_textarea's `From<KeyEvent>` implementation, which for some reason was not working.
@@ -215,6 +567,7 @@ fn to_input(key: KeyEvent) -> Input {
     Input { key, ctrl, alt }
 }
 
+/// Leaves the alternate screen and raw mode, putting the terminal back the way we found it.
 fn leave_terminal(mut terminal: Terminal<CrosstermBackend<Stdout>>) -> std::io::Result<()> {
     execute!(
         terminal.backend_mut(),
@@ -228,8 +581,11 @@ fn leave_terminal(mut terminal: Terminal<CrosstermBackend<Stdout>>) -> std::io::
 
 #[cfg(test)]
 mod tests {
-    use crate::message::Message;
+    use crate::message::{Message, Target, DEFAULT_TIME_FORMAT};
+    use crate::rooms::Rooms;
+    use crate::roster::Roster;
     use crate::terminal::to_input;
+    use crate::theme::Theme;
     use crossterm::event::{
         self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind,
     };
@@ -258,7 +614,9 @@ mod tests {
 
         async fn reciever_test(tx: Sender<Message>) {
             std::thread::sleep(std::time::Duration::from_millis(5000));
-            _ = tx.send(Message::new("Akachi", "I hate you!")).await;
+            _ = tx
+                .send(Message::new("Akachi", "general", "I hate you!"))
+                .await;
         }
 
         let (stx, srx) = channel::<Message>(100);
@@ -271,7 +629,7 @@ mod tests {
             reciever_test(rtx).await;
         });
 
-        _ = stx.send(Message::new("Aeskul", "Hello!")).await;
+        _ = stx.send(Message::new("Aeskul", "general", "Hello!")).await;
         while let Some(m) = rrx.recv().await {
             println!("From Reciever: {m:?}");
         }
@@ -293,17 +651,21 @@ mod tests {
                 .title("Input")
                 .border_type(ratatui::widgets::BorderType::Rounded),
         );
-        let mut msg = TextArea::default();
-        msg.set_block(
-            Block::default()
-                .borders(Borders::ALL)
-                .title("Messages")
-                .border_type(ratatui::widgets::BorderType::Rounded),
-        );
+        let mut msg = Rooms::new("general");
         let mut edit = false;
         loop {
             terminal
-                .draw(|f| crate::terminal::draw_ui(f, &ta, &msg))
+                .draw(|f| {
+                    crate::terminal::draw_ui(
+                        f,
+                        &ta,
+                        &mut msg,
+                        &Roster::default(),
+                        DEFAULT_TIME_FORMAT,
+                        "Aeskul",
+                        &Theme::default(),
+                    )
+                })
                 .unwrap();
 
             if edit {
@@ -347,8 +709,8 @@ mod tests {
         let backend = CrosstermBackend::new(stdout);
         let mut terminal = Terminal::new(backend).unwrap();
 
-        let m = Message::new("Aeskul", "Hello");
-        let o = Message::new("Akachi", "I hate you");
+        let m = Message::new("Aeskul", "general", "Hello");
+        let o = Message::new("Akachi", "general", "I hate you");
 
         let mut ta = TextArea::default();
         ta.set_block(
@@ -357,23 +719,26 @@ mod tests {
                 .title("Input")
                 .border_type(ratatui::widgets::BorderType::Rounded),
         );
-        let mut msg = TextArea::default();
-        msg.set_block(
-            Block::default()
-                .borders(Borders::ALL)
-                .title("Messages")
-                .border_type(ratatui::widgets::BorderType::Rounded),
-        );
+        let mut msg = Rooms::new("general");
         let mut edit = false;
 
-        msg.insert_str(format!("{m}"));
-        msg.insert_newline();
-        msg.insert_str(format!("{o}"));
-        msg.insert_newline();
+        let general = Target::Room("general".to_owned());
+        msg.recieve(&general, m);
+        msg.recieve(&general, o);
 
         loop {
             terminal
-                .draw(|f| crate::terminal::draw_ui(f, &ta, &msg))
+                .draw(|f| {
+                    crate::terminal::draw_ui(
+                        f,
+                        &ta,
+                        &mut msg,
+                        &Roster::default(),
+                        DEFAULT_TIME_FORMAT,
+                        "Aeskul",
+                        &Theme::default(),
+                    )
+                })
                 .unwrap();
 
             if edit {
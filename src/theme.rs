@@ -0,0 +1,318 @@
+use {
+    ratatui::{
+        style::{Color, Style},
+        widgets::{Block, BorderType, Borders},
+    },
+    serde::Deserialize,
+    std::{error::Error, fmt::Display},
+};
+
+/// ### Theme
+///
+/// The colors everything in the terminal is drawn in.
+///
+/// It starts from one of the presets, `dark`, `light` or `high-contrast`, and any color given in the `[theme]` table of the config file replaces the preset's.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Theme {
+    /// The borders around each part of the screen.
+    pub border: Color,
+    /// What the borders are drawn with.
+    pub border_type: BorderType,
+    /// Messages, the input box, and any other plain text.
+    pub text: Color,
+    /// The times messages were sent, day separators, and the boxes around code.
+    pub timestamp: Color,
+    /// Who is typing, and notices from the client itself.
+    pub status: Color,
+    /// Errors.
+    pub error: Color,
+    /// The room being looked at, in the list of rooms.
+    pub selected: Color,
+    /// `code`, and blocks of it.
+    pub code: Color,
+    /// Links.
+    pub link: Color,
+    /// The colors usernames are shown in. Each user always gets the same one, picked by `user_color`.
+    pub users: Vec<Color>,
+}
+
+/// The `[theme]` table of the config file.
+///
+/// ```toml
+/// [theme]
+/// preset = "light"
+/// border_type = "double"
+/// border = "blue"
+/// users = ["red", "#008000", "27"]
+/// ```
+/// Colors are names like `lightblue`, `#rrggbb`, or a number from the terminal's 256 colors.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct ThemeConfig {
+    /// The preset to start from. `dark` if not given.
+    pub preset: Option<String>,
+    /// `plain`, `rounded`, `double` or `thick`.
+    pub border_type: Option<String>,
+    pub border: Option<String>,
+    pub text: Option<String>,
+    pub timestamp: Option<String>,
+    pub status: Option<String>,
+    pub error: Option<String>,
+    pub selected: Option<String>,
+    pub code: Option<String>,
+    pub link: Option<String>,
+    pub users: Option<Vec<String>>,
+}
+
+/// An error from a `[theme]` table that could not be understood.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ThemeError {
+    /// There is no preset with this name.
+    Preset(String),
+    /// There is no border type with this name.
+    BorderType(String),
+    /// The value of `key` is not a color.
+    Color { key: &'static str, value: String },
+    /// `users` was given without any colors in it.
+    NoUserColors,
+}
+
+impl Display for ThemeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Preset(p) => write!(f, "'{p}' is not a theme. Try dark, light or high-contrast."),
+            Self::BorderType(b) => write!(
+                f,
+                "'{b}' is not a border type. Try plain, rounded, double or thick."
+            ),
+            Self::Color { key, value } => {
+                write!(f, "'{value}' is not a color, in the {key} of the theme.")
+            }
+            Self::NoUserColors => write!(f, "The users of the theme need at least one color."),
+        }
+    }
+}
+
+impl Error for ThemeError {}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::dark()
+    }
+}
+
+impl Theme {
+    /// Light text on a dark background.
+    pub fn dark() -> Self {
+        Self {
+            border: Color::White,
+            border_type: BorderType::Rounded,
+            text: Color::Reset,
+            timestamp: Color::DarkGray,
+            status: Color::Gray,
+            error: Color::Red,
+            selected: Color::White,
+            code: Color::Yellow,
+            link: Color::LightBlue,
+            users: vec![
+                Color::Red,
+                Color::Green,
+                Color::Yellow,
+                Color::Blue,
+                Color::Magenta,
+                Color::Cyan,
+                Color::LightRed,
+                Color::LightGreen,
+                Color::LightYellow,
+                Color::LightBlue,
+                Color::LightMagenta,
+                Color::LightCyan,
+            ],
+        }
+    }
+
+    /// Dark text on a light background.
+    pub fn light() -> Self {
+        Self {
+            border: Color::Black,
+            border_type: BorderType::Rounded,
+            text: Color::Black,
+            timestamp: Color::Gray,
+            status: Color::DarkGray,
+            error: Color::Red,
+            selected: Color::Blue,
+            code: Color::Magenta,
+            link: Color::Blue,
+            users: vec![
+                Color::Red,
+                Color::Green,
+                Color::Blue,
+                Color::Magenta,
+                Color::Cyan,
+                Color::Indexed(94),  // Brown
+                Color::Indexed(22),  // Dark green
+                Color::Indexed(18),  // Navy
+                Color::Indexed(90),  // Purple
+                Color::Indexed(130), // Orange
+            ],
+        }
+    }
+
+    /// Only the brightest colors, with thick borders.
+    pub fn high_contrast() -> Self {
+        Self {
+            border: Color::White,
+            border_type: BorderType::Thick,
+            text: Color::White,
+            timestamp: Color::White,
+            status: Color::LightYellow,
+            error: Color::LightRed,
+            selected: Color::LightYellow,
+            code: Color::LightCyan,
+            link: Color::LightYellow,
+            users: vec![
+                Color::LightRed,
+                Color::LightGreen,
+                Color::LightYellow,
+                Color::LightBlue,
+                Color::LightMagenta,
+                Color::LightCyan,
+                Color::White,
+            ],
+        }
+    }
+
+    /// Builds the theme described by the `[theme]` table of the config file.
+    pub fn from_config(config: &ThemeConfig) -> Result<Self, ThemeError> {
+        let mut theme = match config.preset.as_deref() {
+            None | Some("dark") => Self::dark(),
+            Some("light") => Self::light(),
+            Some("high-contrast") => Self::high_contrast(),
+            Some(p) => return Err(ThemeError::Preset(p.to_owned())),
+        };
+
+        if let Some(b) = &config.border_type {
+            theme.border_type = match b.as_str() {
+                "plain" => BorderType::Plain,
+                "rounded" => BorderType::Rounded,
+                "double" => BorderType::Double,
+                "thick" => BorderType::Thick,
+                _ => return Err(ThemeError::BorderType(b.clone())),
+            };
+        }
+
+        let colors = [
+            ("border", &config.border, &mut theme.border),
+            ("text", &config.text, &mut theme.text),
+            ("timestamp", &config.timestamp, &mut theme.timestamp),
+            ("status", &config.status, &mut theme.status),
+            ("error", &config.error, &mut theme.error),
+            ("selected", &config.selected, &mut theme.selected),
+            ("code", &config.code, &mut theme.code),
+            ("link", &config.link, &mut theme.link),
+        ];
+        for (key, value, color) in colors {
+            if let Some(value) = value {
+                *color = parse_color(key, value)?;
+            }
+        }
+
+        if let Some(users) = &config.users {
+            theme.users = users
+                .iter()
+                .map(|u| parse_color("users", u))
+                .collect::<Result<_, _>>()?;
+            if theme.users.is_empty() {
+                return Err(ThemeError::NoUserColors);
+            }
+        }
+        Ok(theme)
+    }
+
+    /// A block with a border all around it, in the theme's border color and type.
+    pub fn block<'a>(&self) -> Block<'a> {
+        Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(self.border))
+            .border_type(self.border_type)
+    }
+
+    /// The color the user `name` is shown in. It only depends on the name, so each user is shown in the same color every time.
+    pub fn user_color(&self, name: &str) -> Color {
+        // FNV-1a, which unlike the standard library's hasher is the same everywhere.
+        let hash = name.bytes().fold(0x811c9dc5u32, |h, b| {
+            (h ^ b as u32).wrapping_mul(0x01000193)
+        });
+        self.users[hash as usize % self.users.len()]
+    }
+}
+
+/// Parses the color given for `key`.
+fn parse_color(key: &'static str, value: &str) -> Result<Color, ThemeError> {
+    value.parse().map_err(|_| ThemeError::Color {
+        key,
+        value: value.to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{Theme, ThemeConfig, ThemeError},
+        ratatui::{style::Color, widgets::BorderType},
+    };
+
+    #[test]
+    fn preset_test() {
+        let config: ThemeConfig = toml::from_str("").unwrap();
+        assert_eq!(Theme::from_config(&config).unwrap(), Theme::dark());
+
+        let config: ThemeConfig = toml::from_str(r#"preset = "high-contrast""#).unwrap();
+        let theme = Theme::from_config(&config).unwrap();
+        assert_eq!(theme, Theme::high_contrast());
+        assert_eq!(theme.border_type, BorderType::Thick);
+
+        let config: ThemeConfig = toml::from_str(r#"preset = "solarized""#).unwrap();
+        assert_eq!(
+            Theme::from_config(&config),
+            Err(ThemeError::Preset("solarized".to_owned()))
+        );
+    }
+
+    #[test]
+    fn override_test() {
+        // Colors given in the config win over the preset's.
+        let config: ThemeConfig = toml::from_str(
+            r##"
+            preset = "light"
+            border_type = "double"
+            border = "light blue"
+            code = "#ff8000"
+            users = ["red", "27"]
+        "##,
+        )
+        .unwrap();
+        let theme = Theme::from_config(&config).unwrap();
+        assert_eq!(theme.border, Color::LightBlue);
+        assert_eq!(theme.border_type, BorderType::Double);
+        assert_eq!(theme.code, Color::Rgb(0xff, 0x80, 0x00));
+        assert_eq!(theme.text, Theme::light().text);
+        assert_eq!(theme.users, [Color::Red, Color::Indexed(27)]);
+
+        // Every user gets a color of their own, the same every time.
+        assert_eq!(theme.user_color("bob"), theme.user_color("bob"));
+        let dark = Theme::dark();
+        assert_ne!(dark.user_color("alice"), dark.user_color("bob"));
+
+        let config: ThemeConfig = toml::from_str(r#"link = "blurple""#).unwrap();
+        assert_eq!(
+            Theme::from_config(&config),
+            Err(ThemeError::Color {
+                key: "link",
+                value: "blurple".to_owned()
+            })
+        );
+        let config: ThemeConfig = toml::from_str("users = []").unwrap();
+        assert_eq!(Theme::from_config(&config), Err(ThemeError::NoUserColors));
+    }
+}